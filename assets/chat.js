@@ -0,0 +1,98 @@
+// A minimal client for the JSON chat protocol (see src/protocol.rs)
+(function () {
+  var PROTOCOL_VERSION = 1;
+
+  var log = document.getElementById("log");
+  var status = document.getElementById("status");
+  var form = document.getElementById("form");
+  var input = document.getElementById("input");
+
+  var scheme = location.protocol === "https:" ? "wss://" : "ws://";
+  var socket = new WebSocket(scheme + location.host + "/chat", "chat.v1");
+
+  function show(kind, from, text) {
+    var line = document.createElement("div");
+    line.className = kind;
+    if (from) {
+      var name = document.createElement("span");
+      name.className = "from";
+      name.textContent = from + " ";
+      line.appendChild(name);
+    }
+    line.appendChild(document.createTextNode(text));
+    log.appendChild(line);
+    log.scrollTop = log.scrollHeight;
+  }
+
+  function send(envelope) {
+    envelope.version = PROTOCOL_VERSION;
+    socket.send(JSON.stringify(envelope));
+  }
+
+  socket.onopen = function () {
+    status.textContent = "Connected";
+  };
+
+  socket.onclose = function (event) {
+    status.textContent = "Disconnected" + (event.reason ? ": " + event.reason : "");
+  };
+
+  socket.onmessage = function (event) {
+    var m = JSON.parse(event.data);
+    var where = m.room ? "[" + m.room + "] " : "";
+
+    switch (m.type) {
+      case "message":
+        show("message", where + m.from + ":", m.body);
+        break;
+      case "emote":
+        show("message", where + "* " + m.from, m.body);
+        break;
+      case "private":
+        show("private", "(private) " + m.from + ":", m.body);
+        break;
+      case "delivered":
+        show("private", "(to " + m.to + ")", m.body);
+        break;
+      case "presence":
+        if (m.body === "nick") {
+          show("presence", null, where + m.from + " is now known as " + m.to);
+        } else {
+          var verbs = { join: "joined", leave: "left", quit: "quit" };
+          show("presence", null, where + m.from + " " + (verbs[m.body] || m.body));
+        }
+        break;
+      case "notice":
+        show("notice", null, m.body);
+        break;
+      case "error":
+        show("error", null, m.body);
+        break;
+      case "who":
+        show("notice", null, "In " + m.room + ": " + m.data.join(", "));
+        break;
+      case "online":
+        show("notice", null, "Online: " + m.data.join(", "));
+        break;
+      case "rooms":
+        show("notice", null, "Rooms: " + m.data.map(function (r) {
+          return r.name + " (" + r.members + ")";
+        }).join(", "));
+        break;
+      default:
+        show("notice", null, event.data);
+    }
+  };
+
+  form.onsubmit = function (event) {
+    event.preventDefault();
+    var text = input.value;
+    if (!text) {
+      return;
+    }
+    // Messages go to the current room, and slash commands are run by the
+    // server
+    send({ type: "message", body: text });
+    input.value = "";
+  };
+})();
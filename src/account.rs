@@ -0,0 +1,449 @@
+use std::collections::{BTreeMap,HashMap};
+use std::fmt;
+use std::fs::{self,File};
+use std::io::{self,Read,Write};
+use std::path::{Path,PathBuf};
+use crypto::scrypt::{self,ScryptParams};
+use nick::Nicks;
+
+/// The shortest password an account may have
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Work factors for hashing passwords:  2^14 iterations, using 16MB.
+/// Hashing runs on the thread pool, but a login still waits for it, so this
+/// is kept moderate.
+const SCRYPT_LOG_N: u8 = 14;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// A hash with the same work factors, checked in place of a missing
+/// account's (and the result ignored), so that an unknown name takes as
+/// long to refuse as a wrong password
+const DUMMY_HASH: &'static str =
+    "$rscrypt$0$DggB$/n0HXTibRHHLOlGg9bYNEA==$eD6NN4cOR1l4IMVnpMhcv7scQLB9BL4UxUTjX9oN0Fw=$";
+
+#[derive(Debug)]
+pub enum AccountError {
+    InvalidName(String),
+    WeakPassword,
+    Taken(String),
+    /// Unknown account or wrong password
+    BadLogin,
+    /// Too many failed logins; the number of seconds until the next try
+    Locked(i64),
+    /// Enough logins to the account are already being checked to lock it
+    Busy,
+    Io(io::Error),
+}
+
+impl AccountError {
+    /// The machine readable code sent in `error` replies
+    pub fn code(&self) -> &'static str {
+        match *self {
+            AccountError::InvalidName(_) => "invalid_nick",
+            AccountError::WeakPassword => "weak_password",
+            AccountError::Taken(_) => "account_taken",
+            AccountError::BadLogin => "bad_login",
+            AccountError::Locked(_) => "account_locked",
+            AccountError::Busy => "account_busy",
+            AccountError::Io(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AccountError::InvalidName(ref name) => write!(f, "Invalid account name: {}", name),
+            AccountError::WeakPassword => {
+                write!(f, "Passwords must be at least {} characters long", MIN_PASSWORD_LEN)
+            },
+            AccountError::Taken(ref name) => write!(f, "{} is already registered", name),
+            AccountError::BadLogin => write!(f, "Wrong account name or password"),
+            AccountError::Locked(seconds) => {
+                write!(f, "Too many failed logins; try again in {} seconds", seconds)
+            },
+            AccountError::Busy => write!(f, "Other logins to this account are being checked; try again shortly"),
+            AccountError::Io(_) => write!(f, "The account could not be saved"),
+        }
+    }
+}
+
+impl From<io::Error> for AccountError {
+    fn from(e: io::Error) -> AccountError {
+        AccountError::Io(e)
+    }
+}
+
+/// Password hashing to be done on the thread pool, as scrypt is too slow
+/// for the event loop.  The `PasswordOutcome` from `run` is handed back to
+/// the `Accounts` method finishing the job.
+pub enum PasswordJob {
+    /// Hash a new account's password
+    Register { name: String, password: String },
+    /// Check a password against an account's hash (None if there is no such
+    /// account)
+    Login { name: String, password: String, hash: Option<String> },
+    /// Check an account's password, and if it matches, hash the new one
+    ChangePassword { name: String, old_password: String, new_password: String, hash: Option<String> },
+}
+
+pub enum PasswordOutcome {
+    Register { name: String, hash: io::Result<String> },
+    Login { name: String, matched: bool },
+    /// The new hash, or None if the old password did not match
+    ChangePassword { name: String, hash: Option<io::Result<String>> },
+}
+
+impl PasswordJob {
+    pub fn run(self) -> PasswordOutcome {
+        match self {
+            PasswordJob::Register { name, password } => {
+                PasswordOutcome::Register { name: name, hash: hash(&password) }
+            },
+            PasswordJob::Login { name, password, hash } => {
+                let matched = check(&name, &password, hash.as_ref().map(|h| &**h));
+                PasswordOutcome::Login { name: name, matched: matched }
+            },
+            PasswordJob::ChangePassword { name, old_password, new_password, hash: old_hash } => {
+                let new_hash = if check(&name, &old_password, old_hash.as_ref().map(|h| &**h)) {
+                    Some(hash(&new_password))
+                } else {
+                    None
+                };
+                PasswordOutcome::ChangePassword { name: name, hash: new_hash }
+            },
+        }
+    }
+}
+
+fn hash(password: &str) -> io::Result<String> {
+    let params = ScryptParams::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P);
+    scrypt::scrypt_simple(password, &params)
+}
+
+/// Whether a password matches an account's hash.  With no account, the
+/// dummy hash is checked all the same.
+fn check(name: &str, password: &str, hash: Option<&str>) -> bool {
+    match scrypt::scrypt_check(password, hash.unwrap_or(DUMMY_HASH)) {
+        Ok(matched) => matched && hash.is_some(),
+        Err(e) => {
+            println!("Bad password hash for account {}: {}", name, e);
+            false
+        },
+    }
+}
+
+/// Failed logins to an account since its last successful one
+struct Failures {
+    count: u32,
+    /// Logins still being checked on the thread pool
+    pending: u32,
+    /// When the lockout ends, in seconds since the unix epoch
+    locked_until: Option<i64>,
+}
+
+/// Local user accounts:  an account name (which is also the nickname it
+/// reserves) and an scrypt password hash, compared case-insensitively like
+/// nicknames.
+///
+/// Accounts are kept in a text file, one "name<TAB>hash" line each, which is
+/// rewritten whole (through a temporary file) on every change.  Failed
+/// logins are only counted in memory.
+pub struct Accounts {
+    path: PathBuf,
+    /// (name as registered, password hash) by lowercased name
+    accounts: BTreeMap<String, (String, String)>,
+    failures: HashMap<String, Failures>,
+    max_failures: u32,
+    lockout_secs: i64,
+}
+
+impl Accounts {
+    /// Load the accounts file, if there is one yet.  After `max_failures`
+    /// failed logins in a row, an account is locked for `lockout_secs`.
+    pub fn open(path: &Path, max_failures: u32, lockout_secs: i64) -> io::Result<Accounts> {
+        let mut accounts = BTreeMap::new();
+
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut file) => { try!(file.read_to_string(&mut contents)); },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => { },
+            Err(e) => return Err(e),
+        }
+
+        for line in contents.lines() {
+            let mut fields = line.splitn(2, '\t');
+            match (fields.next(), fields.next()) {
+                (Some(name), Some(hash)) if !name.is_empty() => {
+                    accounts.insert(name.to_lowercase(), (name.to_owned(), hash.to_owned()));
+                },
+                _ => println!("Skipping malformed line in {}", path.display()),
+            }
+        }
+
+        Ok(Accounts {
+            path: path.to_owned(),
+            accounts: accounts,
+            failures: HashMap::new(),
+            max_failures: max_failures,
+            lockout_secs: lockout_secs,
+        })
+    }
+
+    /// Whether a name (and so the nickname) belongs to an account
+    pub fn exists(&self, name: &str) -> bool {
+        self.accounts.contains_key(&name.to_lowercase())
+    }
+
+    /// Start creating an account:  the password is hashed by the job,
+    /// then `finish_register` adds the account
+    pub fn begin_register(&self, name: &str, password: &str) -> Result<PasswordJob, AccountError> {
+        if !Nicks::is_valid_name(name) {
+            return Err(AccountError::InvalidName(name.to_owned()));
+        }
+        if self.exists(name) {
+            return Err(AccountError::Taken(name.to_owned()));
+        }
+        try!(Self::check_strength(password));
+
+        Ok(PasswordJob::Register { name: name.to_owned(), password: password.to_owned() })
+    }
+
+    pub fn finish_register(&mut self, name: &str, hash: io::Result<String>) -> Result<(), AccountError> {
+        let hash = try!(hash);
+
+        // Someone else may have registered the name while we hashed
+        if self.exists(name) {
+            return Err(AccountError::Taken(name.to_owned()));
+        }
+        self.accounts.insert(name.to_lowercase(), (name.to_owned(), hash));
+
+        if let Err(e) = self.save() {
+            self.accounts.remove(&name.to_lowercase());
+            return Err(AccountError::Io(e));
+        }
+        Ok(())
+    }
+
+    /// Start checking a password, unless the account is locked.  `now` is
+    /// in seconds since the unix epoch.
+    pub fn begin_login(&mut self, name: &str, password: &str, now: i64) -> Result<PasswordJob, AccountError> {
+        let hash = try!(self.begin_check(name, now));
+
+        Ok(PasswordJob::Login { name: name.to_owned(), password: password.to_owned(), hash: hash })
+    }
+
+    /// Count a checked login, returning the account name as registered
+    pub fn finish_login(&mut self, name: &str, matched: bool, now: i64) -> Result<String, AccountError> {
+        let key = name.to_lowercase();
+
+        let registered = match self.accounts.get(&key) {
+            // Not counted by begin_check
+            None => return Err(AccountError::BadLogin),
+            Some(&(ref registered, _)) if matched => Some(registered.clone()),
+            Some(_) => None,
+        };
+
+        let max_failures = self.max_failures;
+        let lockout_secs = self.lockout_secs;
+        let failures = self.failures.entry(key.clone()).or_insert(Failures {
+            count: 0, pending: 1, locked_until: None,
+        });
+        failures.pending -= 1;
+
+        match registered {
+            Some(registered) => {
+                failures.count = 0;
+                failures.locked_until = None;
+                if failures.pending == 0 {
+                    self.failures.remove(&key);
+                }
+                Ok(registered)
+            },
+            None => {
+                failures.count += 1;
+                if failures.count >= max_failures {
+                    println!("Locking account {} for {} seconds after {} failed logins",
+                             name, lockout_secs, failures.count);
+                    failures.count = 0;
+                    failures.locked_until = Some(now + lockout_secs);
+                }
+                Err(AccountError::BadLogin)
+            },
+        }
+    }
+
+    /// Start changing an account's password, given the current one
+    pub fn begin_change_password(&mut self, name: &str, old_password: &str, new_password: &str,
+                                 now: i64) -> Result<PasswordJob, AccountError>
+    {
+        try!(Self::check_strength(new_password));
+        let hash = try!(self.begin_check(name, now));
+
+        Ok(PasswordJob::ChangePassword {
+            name: name.to_owned(),
+            old_password: old_password.to_owned(),
+            new_password: new_password.to_owned(),
+            hash: hash,
+        })
+    }
+
+    /// Store the new hash, if the old password matched
+    pub fn finish_change_password(&mut self, name: &str, hash: Option<io::Result<String>>,
+                                  now: i64) -> Result<(), AccountError>
+    {
+        try!(self.finish_login(name, hash.is_some(), now));
+        let hash = try!(hash.expect("The old password matched"));
+        let key = name.to_lowercase();
+
+        let old_hash = match self.accounts.get_mut(&key) {
+            None => return Err(AccountError::BadLogin),
+            Some(&mut (_, ref mut stored)) => ::std::mem::replace(stored, hash),
+        };
+
+        if let Err(e) = self.save() {
+            if let Some(&mut (_, ref mut stored)) = self.accounts.get_mut(&key) {
+                *stored = old_hash;
+            }
+            return Err(AccountError::Io(e));
+        }
+        Ok(())
+    }
+
+    /// Refuse a login to a locked account, otherwise count it as pending
+    /// and return the account's hash (None if there is no such account).
+    /// Pending logins count towards the lockout, or a flood of guesses
+    /// could all start before the first failure was counted.  Failures are
+    /// only counted for registered accounts, so guessing at made-up names
+    /// cannot fill the map.
+    fn begin_check(&mut self, name: &str, now: i64) -> Result<Option<String>, AccountError> {
+        let key = name.to_lowercase();
+        let max_failures = self.max_failures;
+
+        let hash = match self.accounts.get(&key) {
+            None => return Ok(None),
+            Some(&(_, ref hash)) => hash.clone(),
+        };
+
+        let failures = self.failures.entry(key).or_insert(Failures {
+            count: 0, pending: 0, locked_until: None,
+        });
+
+        if let Some(until) = failures.locked_until {
+            if now < until {
+                return Err(AccountError::Locked(until - now));
+            }
+            failures.locked_until = None;
+        }
+        if failures.count + failures.pending >= max_failures {
+            return Err(AccountError::Busy);
+        }
+        failures.pending += 1;
+
+        Ok(Some(hash))
+    }
+
+    fn check_strength(password: &str) -> Result<(), AccountError> {
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(AccountError::WeakPassword);
+        }
+        Ok(())
+    }
+
+    /// Write every account out, replacing the file only once the new one is
+    /// safely on disk
+    fn save(&self) -> io::Result<()> {
+        let mut contents = String::new();
+        for &(ref name, ref hash) in self.accounts.values() {
+            contents.push_str(&format!("{}\t{}\n", name, hash));
+        }
+
+        let temp_path = self.path.with_extension("tmp");
+        {
+            let mut file = try!(File::create(&temp_path));
+            try!(file.write_all(contents.as_bytes()));
+            try!(file.sync_all());
+        }
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crypto::scrypt;
+    use super::{Accounts, AccountError, PasswordJob, DUMMY_HASH};
+
+    const NOW: i64 = 1_500_000_000;
+
+    /// Accounts with "alice" registered
+    fn accounts() -> Accounts {
+        let mut accounts = Accounts::open(Path::new("/nonexistent/accounts"), 3, 60).unwrap();
+        accounts.accounts.insert("alice".to_owned(), ("alice".to_owned(), DUMMY_HASH.to_owned()));
+        accounts
+    }
+
+    #[test]
+    fn the_dummy_hash_is_really_checked() {
+        // A malformed hash would be refused at once, defeating its purpose
+        assert_eq!(scrypt::scrypt_check("password", DUMMY_HASH).ok(), Some(false));
+    }
+
+    #[test]
+    fn checks_an_unknown_account_against_nothing() {
+        match accounts().begin_login("nobody", "password", NOW).unwrap() {
+            PasswordJob::Login { hash: None, .. } => { },
+            _ => panic!("Expected a login job without a hash"),
+        }
+    }
+
+    #[test]
+    fn does_not_count_failures_for_unknown_accounts() {
+        let mut accounts = accounts();
+        for _ in 0..10 {
+            accounts.begin_login("nobody", "wrong", NOW).unwrap();
+            accounts.finish_login("nobody", false, NOW).unwrap_err();
+        }
+        assert!(accounts.failures.is_empty());
+    }
+
+    #[test]
+    fn locks_after_too_many_failures() {
+        let mut accounts = accounts();
+        for _ in 0..3 {
+            accounts.begin_login("alice", "wrong", NOW).unwrap();
+            match accounts.finish_login("alice", false, NOW) {
+                Err(AccountError::BadLogin) => { },
+                other => panic!("Expected a bad login, got {:?}", other),
+            }
+        }
+
+        match accounts.begin_login("Alice", "password", NOW + 10) {
+            Err(AccountError::Locked(50)) => { },
+            other => panic!("Expected a lockout, got {:?}", other.err()),
+        }
+        assert!(accounts.begin_login("alice", "password", NOW + 60).is_ok());
+    }
+
+    #[test]
+    fn counts_pending_logins_towards_the_lockout() {
+        let mut accounts = accounts();
+        accounts.begin_login("alice", "wrong", NOW).unwrap();
+        accounts.finish_login("alice", false, NOW).unwrap_err();
+        accounts.begin_login("alice", "one", NOW).unwrap();
+        accounts.begin_login("alice", "two", NOW).unwrap();
+
+        match accounts.begin_login("alice", "three", NOW) {
+            Err(AccountError::Busy) => { },
+            other => panic!("Expected busy, got {:?}", other.err()),
+        }
+
+        accounts.finish_login("alice", false, NOW).unwrap_err();
+        accounts.finish_login("alice", false, NOW).unwrap_err();
+        match accounts.begin_login("alice", "four", NOW) {
+            Err(AccountError::Locked(60)) => { },
+            other => panic!("Expected a lockout, got {:?}", other.err()),
+        }
+    }
+}
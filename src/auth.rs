@@ -0,0 +1,258 @@
+//! Authenticating the websocket handshake with signed tokens.
+//!
+//! A token is a JSON Web Token (RFC 7519) signed with HMAC-SHA256 ("HS256")
+//! using the secret in the config.  Its claims must include `sub`, the
+//! identity of the user, and `exp`, when it expires (seconds since the
+//! unix epoch).  `nbf` is checked if present.
+//!
+//! The client may present the token in an `Authorization: Bearer` header,
+//! in a cookie, or in an `access_token` query parameter, in that order of
+//! preference.  The query parameter is for browsers, which cannot set
+//! headers on a websocket handshake.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rustc_serialize::base64::{FromBase64, ToBase64, URL_SAFE};
+use rustc_serialize::json::Json;
+use http_parser::Headers;
+use route::RouteMatch;
+
+/// The query parameter a token may be given in
+pub const TOKEN_PARAM: &'static str = "access_token";
+
+/// Who a client is, from a verified token or a login to a local account
+#[derive(Debug,Clone,PartialEq)]
+pub struct Identity {
+    /// The token's subject (`sub`), or the account name
+    pub subject: String,
+    /// When the token expires, in seconds since the unix epoch.  None for
+    /// an account login.
+    pub expires: Option<i64>,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum AuthError {
+    Missing,
+    Malformed(&'static str),
+    UnsupportedAlgorithm(String),
+    BadSignature,
+    Expired,
+    NotYetValid,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuthError::Missing => write!(f, "No access token"),
+            AuthError::Malformed(what) => write!(f, "Malformed access token: {}", what),
+            AuthError::UnsupportedAlgorithm(ref alg) => write!(f, "Unsupported token algorithm: {}", alg),
+            AuthError::BadSignature => write!(f, "Bad token signature"),
+            AuthError::Expired => write!(f, "Access token has expired"),
+            AuthError::NotYetValid => write!(f, "Access token is not valid yet"),
+        }
+    }
+}
+
+/// The token presented with a handshake request, if any
+pub fn find_token(headers: &Headers, route: &RouteMatch, cookie: &str) -> Option<String> {
+    if let Some(authorization) = headers.get_one("Authorization") {
+        let mut parts = authorization.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("Bearer") => {
+                return Some(token.trim().to_owned());
+            },
+            _ => { },
+        }
+    }
+
+    let from_cookie = headers.get_all("Cookie").into_iter()
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.trim() == cookie => Some(value.trim().to_owned()),
+                _ => None,
+            }
+        })
+        .next();
+    if from_cookie.is_some() {
+        return from_cookie;
+    }
+
+    route.param(TOKEN_PARAM).map(|token| token.to_owned())
+}
+
+/// Check a token's signature and times, returning who it identifies.
+/// `now` is in seconds since the unix epoch.
+pub fn verify(token: &str, secret: &[u8], now: i64) -> Result<Identity, AuthError> {
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return Err(AuthError::Malformed("expected three parts"));
+    }
+
+    let header = try!(decode_json(parts[0]));
+    match header.find("alg").and_then(|alg| alg.as_string()) {
+        Some("HS256") => { },
+        Some(other) => return Err(AuthError::UnsupportedAlgorithm(other.to_owned())),
+        None => return Err(AuthError::Malformed("missing alg")),
+    }
+
+    let signature = match parts[2].from_base64() {
+        Ok(signature) => signature,
+        Err(_) => return Err(AuthError::Malformed("bad signature encoding")),
+    };
+    let signed = token.len() - parts[2].len() - 1;
+    if !fixed_time_eq(&sign(&token.as_bytes()[..signed], secret), &signature) {
+        return Err(AuthError::BadSignature);
+    }
+
+    // Only look at the claims once we know they are ours
+    let claims = try!(decode_json(parts[1]));
+
+    let expires = match claims.find("exp").and_then(|exp| exp.as_i64()) {
+        Some(expires) => expires,
+        None => return Err(AuthError::Malformed("missing exp")),
+    };
+    if now >= expires {
+        return Err(AuthError::Expired);
+    }
+    if let Some(not_before) = claims.find("nbf") {
+        match not_before.as_i64() {
+            Some(not_before) if now < not_before => return Err(AuthError::NotYetValid),
+            Some(_) => { },
+            None => return Err(AuthError::Malformed("bad nbf")),
+        }
+    }
+
+    match claims.find("sub").and_then(|sub| sub.as_string()) {
+        Some(subject) => Ok(Identity { subject: subject.to_owned(), expires: Some(expires) }),
+        None => Err(AuthError::Malformed("missing sub")),
+    }
+}
+
+/// Make a token for `subject` that expires at `expires` (seconds since
+/// the unix epoch)
+pub fn issue(subject: &str, expires: i64, secret: &[u8]) -> String {
+    let mut header = BTreeMap::new();
+    header.insert("alg".to_owned(), Json::String("HS256".to_owned()));
+    header.insert("typ".to_owned(), Json::String("JWT".to_owned()));
+
+    let mut claims = BTreeMap::new();
+    claims.insert("sub".to_owned(), Json::String(subject.to_owned()));
+    claims.insert("exp".to_owned(), Json::I64(expires));
+
+    let mut token = format!("{}.{}",
+                            Json::Object(header).to_string().as_bytes().to_base64(URL_SAFE),
+                            Json::Object(claims).to_string().as_bytes().to_base64(URL_SAFE));
+    let signature = sign(token.as_bytes(), secret).to_base64(URL_SAFE);
+    token.push('.');
+    token.push_str(&signature);
+    token
+}
+
+/// The HMAC-SHA256 of the signed part of a token
+fn sign(signed: &[u8], secret: &[u8]) -> Vec<u8> {
+    let mut hmac = Hmac::new(Sha256::new(), secret);
+    hmac.input(signed);
+    hmac.result().code().to_vec()
+}
+
+fn decode_json(part: &str) -> Result<Json, AuthError> {
+    let bytes = match part.from_base64() {
+        Ok(bytes) => bytes,
+        Err(_) => return Err(AuthError::Malformed("bad encoding")),
+    };
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(_) => return Err(AuthError::Malformed("not UTF-8")),
+    };
+    match Json::from_str(&text) {
+        Ok(json) => if json.is_object() { Ok(json) } else { Err(AuthError::Malformed("not an object")) },
+        Err(_) => Err(AuthError::Malformed("bad JSON")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use rustc_serialize::base64::{ToBase64, URL_SAFE};
+    use rustc_serialize::json::Json;
+    use super::{AuthError, Identity, issue, sign, verify};
+
+    const SECRET: &'static [u8] = b"secret";
+    const NOW: i64 = 1_500_000_000;
+
+    /// A token with these header and claims, signed with SECRET
+    fn token(header: &str, claims: &str) -> String {
+        let signed = format!("{}.{}", header.as_bytes().to_base64(URL_SAFE),
+                             claims.as_bytes().to_base64(URL_SAFE));
+        let signature = sign(signed.as_bytes(), SECRET).to_base64(URL_SAFE);
+        format!("{}.{}", signed, signature)
+    }
+
+    #[test]
+    fn verifies_an_issued_token() {
+        let token = issue("alice", NOW + 60, SECRET);
+        assert_eq!(verify(&token, SECRET, NOW),
+                   Ok(Identity { subject: "alice".to_owned(), expires: Some(NOW + 60) }));
+    }
+
+    #[test]
+    fn refuses_a_bad_signature() {
+        let token = issue("alice", NOW + 60, SECRET);
+        assert_eq!(verify(&token, b"other secret", NOW), Err(AuthError::BadSignature));
+
+        // Claims changed after signing
+        let mut claims = BTreeMap::new();
+        claims.insert("sub".to_owned(), Json::String("mallory".to_owned()));
+        claims.insert("exp".to_owned(), Json::I64(NOW + 60));
+        let parts: Vec<&str> = token.split('.').collect();
+        let forged = format!("{}.{}.{}", parts[0],
+                             Json::Object(claims).to_string().as_bytes().to_base64(URL_SAFE),
+                             parts[2]);
+        assert_eq!(verify(&forged, SECRET, NOW), Err(AuthError::BadSignature));
+    }
+
+    #[test]
+    fn refuses_other_algorithms() {
+        let unsigned = format!("{}.{}.",
+                               r#"{"alg":"none"}"#.as_bytes().to_base64(URL_SAFE),
+                               r#"{"sub":"alice","exp":1600000000}"#.as_bytes().to_base64(URL_SAFE));
+        assert_eq!(verify(&unsigned, SECRET, NOW),
+                   Err(AuthError::UnsupportedAlgorithm("none".to_owned())));
+
+        let no_alg = token(r#"{"typ":"JWT"}"#, r#"{"sub":"alice","exp":1600000000}"#);
+        assert_eq!(verify(&no_alg, SECRET, NOW), Err(AuthError::Malformed("missing alg")));
+    }
+
+    #[test]
+    fn checks_the_times() {
+        let expiring = issue("alice", NOW, SECRET);
+        assert_eq!(verify(&expiring, SECRET, NOW - 1).map(|identity| identity.subject),
+                   Ok("alice".to_owned()));
+        assert_eq!(verify(&expiring, SECRET, NOW), Err(AuthError::Expired));
+
+        let header = r#"{"alg":"HS256"}"#;
+        let early = token(header, r#"{"sub":"alice","exp":1600000000,"nbf":1500000100}"#);
+        assert_eq!(verify(&early, SECRET, NOW), Err(AuthError::NotYetValid));
+        assert!(verify(&early, SECRET, NOW + 100).is_ok());
+    }
+
+    #[test]
+    fn refuses_malformed_tokens() {
+        let header = r#"{"alg":"HS256"}"#;
+        assert_eq!(verify("abc", SECRET, NOW), Err(AuthError::Malformed("expected three parts")));
+        assert_eq!(verify(&token(header, r#"{"sub":"alice"}"#), SECRET, NOW),
+                   Err(AuthError::Malformed("missing exp")));
+        assert_eq!(verify(&token(header, r#"{"exp":1600000000}"#), SECRET, NOW),
+                   Err(AuthError::Malformed("missing sub")));
+        assert_eq!(verify(&token(header, r#"{"sub":"alice","exp":1600000000,"nbf":"soon"}"#),
+                          SECRET, NOW),
+                   Err(AuthError::Malformed("bad nbf")));
+        assert_eq!(verify(&token(header, "[]"), SECRET, NOW), Err(AuthError::Malformed("not an object")));
+    }
+}
@@ -1,22 +1,51 @@
 
 use std::io::{Read,Write,ErrorKind};
+use std::net::Shutdown;
 use mio::tcp::TcpStream;
 use mio::{Token,EventLoop,EventSet,PollOpt,Sender};
 use handler::EventHandler;
 use event_message::EventMessage;
-use http_parser::HttpParser;
+use http_parser::{self, HttpParser, HttpRequest};
 use http_muncher::Parser;
-use sha1;
-use websocket_frame::{WebSocketFrame, OpCode};
+use websocket_frame::{self, WebSocketFrame, OpCode, CloseCode};
+use config::Config;
+use static_files;
+use origin;
+use route::RouteMatch;
+use auth::{self, Identity};
+use extension::Extensions;
 use time;
 
-use rustc_serialize::base64::{ToBase64, STANDARD};
-use std::collections::HashMap;
+use rustc_serialize::base64::FromBase64;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::fmt;
 
 
+/// The only websocket protocol version we speak
+const WEBSOCKET_VERSION: &'static str = "13";
+
+/// The most frames decoded for one readable event.  Each frame may queue an
+/// event for the server, and the server's queue is bounded, so one client
+/// must not be able to flood it; the rest wait for the next turn.
+const MAX_FRAMES_PER_READ: usize = 64;
+
+/// Why a websocket handshake was refused
+enum Rejection {
+    /// 400 Bad Request:  a broken handshake
+    BadRequest(String),
+    /// 401 Unauthorized:  without a valid access token
+    Unauthorized(String),
+    /// 403 Forbidden:  from an origin that is not allowed
+    Forbidden(String),
+    /// 404 Not Found:  for a path with no websocket service
+    NotFound(String),
+    /// 426 Upgrade Required:  for a version of the protocol we do not speak
+    UpgradeRequired(String),
+    /// 431 Request Header Fields Too Large
+    HeadersTooLarge,
+}
+
 #[derive(Debug,PartialEq,Eq,PartialOrd,Ord)]
 pub enum ClientState {
     New,
@@ -24,6 +53,15 @@ pub enum ClientState {
     HandshakeResponse,
     Running,
     RunningAndWriting,
+    /// A close frame has been queued, and we are finishing the close
+    /// handshake:  flushing our close frame and/or waiting for the peer's
+    Closing,
+    /// The close handshake is over and the TCP stream has been shut down
+    Closed,
+    /// A plain HTTP response (a static file, or a refused handshake) is
+    /// being written.  Afterwards the connection waits for the next request
+    /// if it is kept alive, and is dropped otherwise.
+    HttpResponse,
 }
 
 impl ClientState {
@@ -34,42 +72,86 @@ impl ClientState {
             ClientState::HandshakeResponse => ClientState::Running,
             ClientState::Running => ClientState::Running,
             ClientState::RunningAndWriting => ClientState::Running,
+            ClientState::Closing => ClientState::Closing,
+            ClientState::Closed => ClientState::Closed,
+            ClientState::HttpResponse => ClientState::HttpResponse,
         }
     }
 }
 
 pub struct Client {
     pub token: Token,
+    /// The nickname this client has claimed, if any
+    pub nick: Option<String>,
+    /// The room this client's messages are sent to
+    pub room: Option<String>,
+    /// The subprotocol agreed in the handshake, if the client asked for one
+    pub protocol: Option<String>,
+    /// The service this client connected to, with its query parameters
+    pub route: Option<RouteMatch>,
+    /// Who the client is, from its access token or an account login
+    pub identity: Option<Identity>,
     socket: TcpStream,
     sender: Sender<EventMessage>,
+    config: Arc<Config>,
     state: ClientState,
     outgoing: Vec<u8>,
-    headers: Arc<Mutex<HashMap<String, String>>>,
+    /// Bytes read but not yet decoded into frames
+    incoming: Vec<u8>,
+    request: Arc<Mutex<HttpRequest>>,
     http_parser: Parser<HttpParser>,
     ping_sent: Option<time::Tm>,
     ping_payload: Vec<u8>,
-    closing: bool,
+    pings_sent: u64,
+    missed_pongs: u32,
+    close_sent: bool,
+    close_received: bool,
+    /// The opcode, reserved bits and payload so far of a fragmented
+    /// message
+    fragments: Option<(OpCode, u8, Vec<u8>)>,
+    /// The extensions negotiated in the handshake
+    extensions: Extensions,
+    /// Set once the client has broken the protocol and is being closed
+    failed: bool,
+    /// Whether to wait for another request once the HTTP response is written
+    keep_alive: bool,
+    /// HTTP responses written on this connection, so that a request timeout
+    /// scheduled for an earlier request can tell it is stale
+    responses_sent: u64,
 }
 
 impl Client {
-    pub fn new(socket: TcpStream, token: Token, sender: Sender<EventMessage>) -> Client
+    pub fn new(socket: TcpStream, token: Token, sender: Sender<EventMessage>,
+               config: Arc<Config>) -> Client
     {
-        let headers = Arc::new(Mutex::new(HashMap::new()));
+        let request = Arc::new(Mutex::new(HttpRequest::new()));
 
         Client {
             socket: socket,
             token: token,
+            nick: None,
+            room: None,
+            protocol: None,
+            route: None,
+            identity: None,
             sender: sender,
+            config: config,
             state: ClientState::New,
             outgoing: Vec::with_capacity(1024),
-            headers: headers.clone(),
-            http_parser: Parser::request(HttpParser{
-                current_key: None,
-                headers: headers.clone(),
-            }),
+            incoming: Vec::with_capacity(1024),
+            request: request.clone(),
+            http_parser: Parser::request(HttpParser::new(request.clone())),
             ping_sent: None,
             ping_payload: b"joist".to_vec(),
-            closing: false,
+            pings_sent: 0,
+            missed_pongs: 0,
+            close_sent: false,
+            close_received: false,
+            fragments: None,
+            extensions: Extensions::new(),
+            failed: false,
+            keep_alive: false,
+            responses_sent: 0,
         }
     }
 
@@ -89,10 +171,19 @@ impl Client {
 
         let event_set = EventSet::hup() | match self.state {
             ClientState::New => unreachable!("Handled above"),
-            ClientState::HandshakeResponse => EventSet::writable(),
+            ClientState::HandshakeResponse | ClientState::HttpResponse => EventSet::writable(),
             ClientState::AwaitingHandshake => EventSet::readable(),
             ClientState::Running => EventSet::readable(),
             ClientState::RunningAndWriting => EventSet::writable() | EventSet::readable(),
+            ClientState::Closing => {
+                if !self.outgoing.is_empty() {
+                    EventSet::writable()
+                } else {
+                    // Waiting for the peer's close frame
+                    EventSet::readable()
+                }
+            },
+            ClientState::Closed => return,
         };
 
         event_loop.reregister(&self.socket,
@@ -106,8 +197,9 @@ impl Client {
         match self.state {
             ClientState::New | ClientState::HandshakeResponse => {
                 println!("Event out of step: Readable, but {:?}", self.state);
-                self.sender.send(EventMessage::ReArm(self.token)).unwrap();
+                self.notify(EventMessage::ReArm(self.token));
             },
+            ClientState::Closed | ClientState::HttpResponse => { },
             ClientState::AwaitingHandshake =>
             {
                 let mut buf: [u8; 1024] = [0; 1024];
@@ -117,7 +209,7 @@ impl Client {
                             match e.kind() {
                                 ErrorKind::WouldBlock => {
                                     // Remain in the current state, and re-arm for further reading
-                                    self.sender.send(EventMessage::ReArm(self.token)).unwrap();
+                                    self.notify(EventMessage::ReArm(self.token));
                                     break;
                                 },
                                 ErrorKind::Interrupted => {
@@ -125,88 +217,453 @@ impl Client {
                                 },
                                 _other => {
                                     println!("Read error: {:?}",e);
-                                    self.sender.send(EventMessage::Close(self.token)).unwrap();
+                                    self.notify(EventMessage::Close(self.token));
                                     break;
                                 }
                             }
                         },
                         Ok(0) => {
-                            // We are done.  Re-Arm for the next event.
-                            self.sender.send(EventMessage::ReArm(self.token)).unwrap();
+                            // The peer went away before finishing the handshake
+                            self.notify(EventMessage::Close(self.token));
                             break;
                         },
                         Ok(size) => {
-                            //self.incoming.extend_from_slice(&buf[..size]);
-                            match self.state {
-                                ClientState::AwaitingHandshake => {
+                            let parsed = self.http_parser.parse(&buf[..size]);
 
-                                    self.http_parser.parse(&buf[..size]);
-
-                                    if self.http_parser.is_upgrade() {
-                                        let headers = self.headers.lock().unwrap();
-
-                                        let mut m = sha1::Sha1::new();
-                                        let mut rbuf = [0u8; 20];
+                            if self.request.lock().unwrap().too_large {
+                                self.reject_handshake(Rejection::HeadersTooLarge);
+                                self.notify(EventMessage::ReArm(self.token));
+                                break;
+                            }
 
-                                        m.update(&headers.get("Sec-WebSocket-Key").unwrap().as_bytes());
-                                        m.update("258EAFA5-E914-47DA-95CA-C5AB0DC85B11".as_bytes());
+                            if self.http_parser.has_error() {
+                                self.reject_handshake(Rejection::BadRequest("Malformed request".to_owned()));
+                                self.notify(EventMessage::ReArm(self.token));
+                                break;
+                            }
 
-                                        m.output(&mut rbuf);
+                            if !self.request.lock().unwrap().complete {
+                                continue; // in case there is more to read
+                            }
 
-                                        let response = fmt::format(format_args!("HTTP/1.1 101 Switching Protocols\r\n\
-                                                                                 Connection: Upgrade\r\n\
-                                                                                 Sec-WebSocket-Accept: {}\r\n\
-                                                                                 Upgrade: websocket\r\n\r\n", rbuf.to_base64(STANDARD)));
+                            let upgrade = self.request.lock().unwrap().headers.has_token("Upgrade", "websocket");
+                            if !upgrade {
+                                self.serve_http();
+                                self.notify(EventMessage::ReArm(self.token));
+                                break;
+                            }
 
-                                        self.outgoing.extend_from_slice(response.as_bytes());
+                            // Anything after the request is already websocket
+                            // frames
+                            if parsed < size {
+                                self.incoming.extend_from_slice(&buf[parsed..size]);
+                            }
 
-                                        self.state = ClientState::RunningAndWriting;
+                            match self.accept_handshake() {
+                                Ok(()) => { self.process_incoming(MAX_FRAMES_PER_READ); },
+                                Err(rejection) => self.reject_handshake(rejection),
+                            }
 
-                                        self.sender.send(EventMessage::ReArm(self.token)).unwrap();
+                            self.notify(EventMessage::ReArm(self.token));
+                            break;
+                        }
+                    }
+                }
+            },
+            ClientState::Running | ClientState::RunningAndWriting | ClientState::Closing => {
+                // Frames left over from the last turn come first
+                let mut budget = MAX_FRAMES_PER_READ - self.process_incoming(MAX_FRAMES_PER_READ);
 
-                                        break;
-                                    }
+                let mut buf: [u8; 4096] = [0; 4096];
+                while budget > 0 {
+                    match self.socket.read(&mut buf[..]) {
+                        Err(e) => {
+                            match e.kind() {
+                                ErrorKind::WouldBlock => {
+                                    // Everything available has been read
+                                    break;
+                                },
+                                ErrorKind::Interrupted => {
                                     continue; // in case there is more to read
                                 },
-                                _ => { }
+                                _other => {
+                                    println!("Read error: {:?}",e);
+                                    self.notify(EventMessage::Close(self.token));
+                                    return;
+                                }
                             }
+                        },
+                        Ok(0) => {
+                            // The peer has closed the TCP stream
+                            self.notify(EventMessage::Close(self.token));
+                            return;
+                        },
+                        Ok(size) => {
+                            self.incoming.extend_from_slice(&buf[..size]);
+                            budget -= self.process_incoming(budget);
+                            continue; // in case there is more to read
                         }
                     }
                 }
+
+                if budget == 0 {
+                    // There may be more buffered, or still on the socket
+                    self.notify(EventMessage::ReadMore(self.token));
+                } else {
+                    self.notify(EventMessage::ReArm(self.token));
+                }
             },
-            ClientState::Running | ClientState::RunningAndWriting => {
-                let frame = WebSocketFrame::read(&mut self.socket);
-
-                match frame {
-                    Ok(frame) => {
-                        match frame.get_opcode() {
-                            OpCode::TextFrame => {
-                                let payload = String::from_utf8(frame.payload).unwrap();
-                                self.sender.send(EventMessage::TextFrame(self.token, payload)).unwrap();
-                            },
-                            OpCode::BinaryFrame => {
-                                self.sender.send(EventMessage::BinaryFrame(self.token, frame.payload)).unwrap();
-                            },
-                            OpCode::ConnectionClose => {
-                                self.sender.send(EventMessage::Close(self.token)).unwrap();
-                            },
-                            OpCode::Ping => {
-                                self.sender.send(EventMessage::Ping(self.token, frame)).unwrap();
-                            },
-                            OpCode::Pong => {
-                                self.sender.send(EventMessage::Pong(self.token, frame.payload)).unwrap();
-                            },
-                        }
+        }
+    }
 
-                        // self.state = self.state.next();
-                        self.sender.send(EventMessage::ReArm(self.token)).unwrap();
-                    }
-                    Err(e) => println!("error while reading frame: {}", e)
+    /// Check the handshake request and, if it is acceptable, queue the 101
+    /// response and start running
+    fn accept_handshake(&mut self) -> Result<(), Rejection>
+    {
+        let request = self.request.clone();
+        let request = request.lock().unwrap();
+
+        let key = try!(Self::validate_handshake(&self.http_parser, &request));
+        let accept = http_parser::accept_key(&key);
+
+        let config = self.config.clone();
+
+        let route = match RouteMatch::find(&config.routes, &request.url) {
+            Some(route) => route,
+            None => return Err(Rejection::NotFound(format!("No websocket service at {}",
+                                                           http_parser::target_path(&request.url)))),
+        };
+        let service = config.routes.get(&route.path).expect("The route was just found");
+
+        if request.headers.contains("Origin") {
+            match request.headers.get_one("Origin") {
+                None => return Err(Rejection::BadRequest("More than one Origin header".to_owned())),
+                Some(origin) => if !origin::is_allowed(origin, request.headers.get_one("Host"),
+                                                       &config.allowed_origins) {
+                    return Err(Rejection::Forbidden(format!("Origin {} is not allowed", origin)));
+                },
+            }
+        }
+
+        let identity = match config.auth_secret {
+            None => None,
+            Some(ref secret) => {
+                let verified = match auth::find_token(&request.headers, &route, &config.auth_cookie) {
+                    None => Err(auth::AuthError::Missing),
+                    Some(token) => auth::verify(&token, secret, time::get_time().sec),
+                };
+                match verified {
+                    Ok(identity) => {
+                        println!("Client {:?} authenticated as {}", self.token, identity.subject);
+                        Some(identity)
+                    },
+                    Err(e) => return Err(Rejection::Unauthorized(format!("{}", e))),
                 }
             },
+        };
+
+        let protocol = match request.headers.get_joined("Sec-WebSocket-Protocol") {
+            None => None,
+            Some(offered) => match Self::select_subprotocol(&offered, &service.subprotocols) {
+                None => return Err(Rejection::BadRequest(
+                    "None of the offered subprotocols are supported".to_owned())),
+                protocol => protocol,
+            },
+        };
+        let protocol_header = match protocol {
+            Some(ref protocol) => format!("Sec-WebSocket-Protocol: {}\r\n", protocol),
+            None => String::new(),
+        };
+        self.protocol = protocol;
+        self.route = Some(route);
+        self.identity = identity;
+
+        if let Some(offered) = request.headers.get_joined("Sec-WebSocket-Extensions") {
+            self.extensions = Extensions::negotiate(&offered, &config.extensions);
+        }
+        let extensions_header = match self.extensions.response() {
+            Some(response) => format!("Sec-WebSocket-Extensions: {}\r\n", response),
+            None => String::new(),
+        };
+
+        let response = fmt::format(format_args!("HTTP/1.1 101 Switching Protocols\r\n\
+                                                 Connection: Upgrade\r\n\
+                                                 Sec-WebSocket-Accept: {}\r\n\
+                                                 {}\
+                                                 {}\
+                                                 Upgrade: websocket\r\n\r\n",
+                                                accept,
+                                                protocol_header,
+                                                extensions_header));
+
+        self.outgoing.extend_from_slice(response.as_bytes());
+
+        self.state = ClientState::RunningAndWriting;
+
+        Ok(())
+    }
+
+    /// Check that a request is a websocket handshake we can accept (RFC
+    /// 6455, section 4.2.1), returning its Sec-WebSocket-Key
+    fn validate_handshake(parser: &Parser<HttpParser>, request: &HttpRequest) -> Result<String, Rejection>
+    {
+        if parser.http_method() != "GET" {
+            return Err(Rejection::BadRequest("The handshake must be a GET request".to_owned()));
+        }
+        if parser.http_version() < (1, 1) {
+            return Err(Rejection::BadRequest("The handshake must be HTTP/1.1 or later".to_owned()));
+        }
+        if request.headers.get_one("Host").is_none() {
+            return Err(Rejection::BadRequest("Missing Host header".to_owned()));
+        }
+        if !request.headers.has_token("Connection", "Upgrade") {
+            return Err(Rejection::BadRequest("The Connection header must include Upgrade".to_owned()));
+        }
+        if request.headers.get_one("Sec-WebSocket-Version") != Some(WEBSOCKET_VERSION) {
+            return Err(Rejection::UpgradeRequired("Unsupported websocket version".to_owned()));
+        }
+
+        // The key must be 16 bytes, base64 encoded
+        match request.headers.get_one("Sec-WebSocket-Key") {
+            Some(key) if key.from_base64().map(|k| k.len() == 16).unwrap_or(false) => Ok(key.to_owned()),
+            _ => Err(Rejection::BadRequest("Missing or invalid Sec-WebSocket-Key".to_owned())),
         }
     }
 
+    /// The first of the client's offered subprotocols (a comma separated
+    /// list) that we support
+    fn select_subprotocol(offered: &str, supported: &[String]) -> Option<String> {
+        offered.split(',')
+            .map(|protocol| protocol.trim())
+            .find(|protocol| supported.iter().any(|s| s == protocol))
+            .map(|protocol| protocol.to_owned())
+    }
+
+    /// Answer a request that is not a websocket handshake from the static
+    /// files
+    fn serve_http(&mut self)
+    {
+        let method = self.http_parser.http_method();
+        self.keep_alive = self.http_parser.should_keep_alive() && (method == "GET" || method == "HEAD");
+
+        let response = {
+            let request = self.request.lock().unwrap();
+            static_files::serve(self.config.static_dir.as_ref().map(|dir| &**dir),
+                                method, &request.url, &request.headers, self.keep_alive)
+        };
+
+        self.outgoing.extend_from_slice(&response);
+
+        self.state = ClientState::HttpResponse;
+    }
+
+    /// Refuse the websocket handshake with an HTTP error response, then drop
+    /// the connection once it is written
+    fn reject_handshake(&mut self, rejection: Rejection)
+    {
+        let mut headers = vec![("Connection", "close".to_owned()),
+                               ("Content-Type", "text/plain".to_owned())];
+
+        let (status, reason) = match rejection {
+            Rejection::BadRequest(reason) => ("400 Bad Request", reason),
+            Rejection::Unauthorized(reason) => {
+                headers.push(("WWW-Authenticate", "Bearer".to_owned()));
+                ("401 Unauthorized", reason)
+            },
+            Rejection::Forbidden(reason) => ("403 Forbidden", reason),
+            Rejection::NotFound(reason) => ("404 Not Found", reason),
+            Rejection::UpgradeRequired(reason) => {
+                headers.push(("Sec-WebSocket-Version", WEBSOCKET_VERSION.to_owned()));
+                headers.push(("Upgrade", "websocket".to_owned()));
+                ("426 Upgrade Required", reason)
+            },
+            Rejection::HeadersTooLarge => {
+                ("431 Request Header Fields Too Large", "Too many headers, or headers too long".to_owned())
+            },
+        };
+
+        println!("Rejecting handshake from client {:?}: {}", self.token, reason);
+
+        let response = http_parser::response_head(status, &headers, reason.len()) + &reason;
+
+        self.incoming.clear();
+        self.outgoing.extend_from_slice(response.as_bytes());
+
+        self.keep_alive = false;
+        self.state = ClientState::HttpResponse;
+    }
+
+    /// Get ready to read the next request on a kept alive connection
+    fn await_next_request(&mut self)
+    {
+        self.request = Arc::new(Mutex::new(HttpRequest::new()));
+        self.http_parser = Parser::request(HttpParser::new(self.request.clone()));
+        self.state = ClientState::AwaitingHandshake;
+        self.responses_sent += 1;
+    }
+
+    /// Handle up to `limit` complete frames from the input buffer, leaving
+    /// the rest (and any partial frame) there for later.  Returns the number
+    /// of frames handled.
+    fn process_incoming(&mut self, limit: usize) -> usize
+    {
+        let mut consumed = 0;
+        let mut handled = 0;
+
+        let allowed_rsv = self.extensions.rsv_bits();
+        let max_frame_size = self.config.max_frame_size;
+
+        while !self.failed && !self.close_received && handled < limit {
+            match WebSocketFrame::parse(&self.incoming[consumed..], allowed_rsv, max_frame_size) {
+                Ok(None) => break,
+                Ok(Some((frame, used))) => {
+                    consumed += used;
+                    handled += 1;
+                    self.handle_frame(frame);
+                },
+                Err(e) => {
+                    self.fail(e.close_code(), &format!("{}", e));
+                },
+            }
+        }
+
+        if self.failed || self.close_received {
+            // Nothing more from this client will be understood, or may be
+            // sent after its close frame
+            self.incoming.clear();
+        } else {
+            self.incoming.drain(..consumed);
+        }
+
+        handled
+    }
+
+    fn handle_frame(&mut self, frame: WebSocketFrame)
+    {
+        if frame.rsv_bits() != 0 && frame.get_opcode().is_control() {
+            // Extensions only apply to messages
+            self.fail(CloseCode::ProtocolError, "reserved bits set on a control frame");
+            return;
+        }
+
+        match frame.get_opcode() {
+            OpCode::ConnectionClose => {
+                self.close_received = true;
+                self.notify(EventMessage::CloseRequest(self.token, frame));
+            },
+            _ if self.state == ClientState::Closing => {
+                // Once closing, only the peer's close frame matters
+            },
+            OpCode::TextFrame | OpCode::BinaryFrame => {
+                let rsv_bits = frame.rsv_bits();
+
+                if self.fragments.is_some() {
+                    self.fail(CloseCode::ProtocolError, "new message before the last was finished");
+                } else if frame.payload.len() > self.config.max_message_size {
+                    self.fail(CloseCode::MessageTooBig, "message too big");
+                } else if frame.is_final() {
+                    self.deliver_message(frame.get_opcode(), rsv_bits, frame.payload);
+                } else {
+                    self.fragments = Some((frame.get_opcode(), rsv_bits, frame.payload));
+                }
+            },
+            OpCode::Continuation => {
+                if frame.rsv_bits() != 0 {
+                    // A message's reserved bits are set on its first frame
+                    self.fail(CloseCode::ProtocolError, "reserved bits set on a continuation frame");
+                    return;
+                }
+
+                let (opcode, rsv_bits, mut payload) = match self.fragments.take() {
+                    None => {
+                        self.fail(CloseCode::ProtocolError, "continuation without a message to continue");
+                        return;
+                    },
+                    Some(fragments) => fragments,
+                };
+
+                if payload.len() + frame.payload.len() > self.config.max_message_size {
+                    self.fail(CloseCode::MessageTooBig, "message too big");
+                    return;
+                }
+
+                payload.extend_from_slice(&frame.payload);
+
+                if frame.is_final() {
+                    self.deliver_message(opcode, rsv_bits, payload);
+                } else {
+                    self.fragments = Some((opcode, rsv_bits, payload));
+                }
+            },
+            OpCode::Ping => {
+                self.notify(EventMessage::Ping(self.token, frame));
+            },
+            OpCode::Pong => {
+                self.notify(EventMessage::Pong(self.token, frame.payload));
+            },
+        }
+    }
+
+    /// Pass a complete (possibly reassembled) data message to the server,
+    /// once the extensions have decoded it
+    fn deliver_message(&mut self, opcode: OpCode, rsv_bits: u8, payload: Vec<u8>)
+    {
+        let payload = if self.extensions.is_empty() {
+            payload
+        } else {
+            let mut message = WebSocketFrame::data(opcode, payload);
+            message.set_rsv_bits(rsv_bits);
+            match self.extensions.decode(message, self.config.max_message_size) {
+                Ok(message) => message.payload,
+                Err(e) => {
+                    self.fail(e.code, &format!("{}", e));
+                    return;
+                },
+            }
+        };
+
+        match opcode {
+            OpCode::TextFrame => {
+                match websocket_frame::decode_text(payload) {
+                    Ok(text) => {
+                        self.notify(EventMessage::TextFrame(self.token, text));
+                    },
+                    Err(e) => {
+                        self.fail(e.close_code(), &format!("{}", e));
+                    },
+                }
+            },
+            OpCode::BinaryFrame => {
+                self.notify(EventMessage::BinaryFrame(self.token, payload));
+            },
+            _ => unreachable!("Only data frames carry messages"),
+        }
+    }
+
+    /// Pass an event to the server.  If its queue is full, this client is
+    /// sending faster than it can be served, so it is cut off; the server
+    /// forgets it at the next heartbeat.
+    fn notify(&mut self, message: EventMessage)
+    {
+        if self.sender.send(message).is_err() {
+            if self.state != ClientState::Closed {
+                println!("Client {:?} cut off: the server is not keeping up", self.token);
+            }
+            self.failed = true;
+            self.shutdown();
+        }
+    }
+
+    /// Have the server close this client for breaking the protocol
+    fn fail(&mut self, status_code: CloseCode, reason: &str)
+    {
+        println!("Client {:?} failed: {}", self.token, reason);
+
+        self.fragments = None;
+        self.failed = true;
+        self.notify(EventMessage::Fail(self.token, status_code, reason.to_owned()));
+    }
+
     pub fn handle_writable(&mut self)
     {
         match self.state {
@@ -214,9 +671,11 @@ impl Client {
                 | ClientState::Running =>
             {
                 println!("Event out of step: Writable, but {:?}", self.state);
-                self.sender.send(EventMessage::ReArm(self.token)).unwrap();
+                self.notify(EventMessage::ReArm(self.token));
             },
-            ClientState::HandshakeResponse | ClientState::RunningAndWriting =>
+            ClientState::Closed => { },
+            ClientState::HandshakeResponse | ClientState::RunningAndWriting
+                | ClientState::Closing | ClientState::HttpResponse =>
             {
                 loop {
                     match self.socket.write(&mut self.outgoing) {
@@ -224,7 +683,7 @@ impl Client {
                             match e.kind() {
                                 ErrorKind::WouldBlock => {
                                     // Remain in the current state, and re-arm for further writing
-                                    self.sender.send(EventMessage::ReArm(self.token)).unwrap();
+                                    self.notify(EventMessage::ReArm(self.token));
                                     break;
                                 },
                                 ErrorKind::Interrupted => {
@@ -232,20 +691,33 @@ impl Client {
                                 },
                                 _other => {
                                     println!("Write error: {:?}",e);
-                                    self.sender.send(EventMessage::Close(self.token)).unwrap();
+                                    self.notify(EventMessage::Close(self.token));
                                     break;
                                 }
                             }
                         },
                         Ok(0) => {
                             // We are done.  Re-Arm for the next event.
-                            self.sender.send(EventMessage::ReArm(self.token)).unwrap();
+                            self.notify(EventMessage::ReArm(self.token));
                             break;
                         },
                         Ok(size) if size == self.outgoing.len() => {
                             self.outgoing.truncate(0);
                             self.state = self.state.next();
-                            self.sender.send(EventMessage::ReArm(self.token)).unwrap();
+                            if self.state == ClientState::Closing && self.close_received {
+                                // Both close frames have now been exchanged
+                                self.finish_close();
+                            } else if self.state == ClientState::HttpResponse {
+                                // The response is out
+                                if self.keep_alive {
+                                    self.await_next_request();
+                                    self.notify(EventMessage::AwaitingRequest(self.token));
+                                } else {
+                                    self.finish_close();
+                                }
+                            } else {
+                                self.notify(EventMessage::ReArm(self.token));
+                            }
                             break;
                         },
                         Ok(size) => {
@@ -262,20 +734,90 @@ impl Client {
 
     pub fn send_frame(&mut self, outbound_frame: WebSocketFrame)
     {
+        if !self.is_running() {
+            // Nothing may follow a close frame
+            return;
+        }
+
+        let outbound_frame = match self.encode_frame(&outbound_frame) {
+            Some(encoded) => encoded,
+            None => outbound_frame,
+        };
+
         outbound_frame.write(&mut self.outgoing).unwrap();
 
         self.state = ClientState::RunningAndWriting;
     }
 
+    /// Pass an outgoing message through the extensions, returning None if
+    /// it goes out unchanged.  Control frames and fragments are never
+    /// changed.
+    fn encode_frame(&mut self, frame: &WebSocketFrame) -> Option<WebSocketFrame> {
+        if !frame.is_data() || !frame.is_final() || self.extensions.is_empty() {
+            return None;
+        }
+
+        self.extensions.encode(frame)
+    }
+
+    /// Handle the peer's close frame.  If the peer started the close
+    /// handshake, its status code is echoed back; if we did, the handshake
+    /// is complete once our own close frame is out.
     pub fn handle_close_request(&mut self, close_frame: WebSocketFrame)
     {
         println!("Close request received");
 
-        if !self.closing
-        {
-            self.closing = true;
-            self.send_frame(WebSocketFrame::close_from(&close_frame));
+        self.close_received = true;
+
+        if !self.close_sent {
+            self.queue_close_frame(WebSocketFrame::close_from(&close_frame));
+        } else if self.outgoing.is_empty() {
+            self.finish_close();
+        }
+    }
+
+    /// Start the close handshake from our side
+    pub fn close(&mut self, status_code: CloseCode, reason: &str)
+    {
+        if self.close_sent {
+            return;
         }
+
+        self.queue_close_frame(WebSocketFrame::close(status_code, reason.as_bytes()).unwrap());
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.state == ClientState::Closing
+    }
+
+    /// Whether the TCP stream has been shut down
+    pub fn is_closed(&self) -> bool {
+        self.state == ClientState::Closed
+    }
+
+    /// Shut the TCP stream down without waiting for the close handshake
+    pub fn shutdown(&mut self)
+    {
+        let _ = self.socket.shutdown(Shutdown::Both);
+
+        self.state = ClientState::Closed;
+    }
+
+    fn queue_close_frame(&mut self, close_frame: WebSocketFrame)
+    {
+        close_frame.write(&mut self.outgoing).unwrap();
+
+        self.close_sent = true;
+        self.state = ClientState::Closing;
+    }
+
+    /// The close handshake is complete:  shut the TCP stream down and have
+    /// the server forget this client
+    fn finish_close(&mut self)
+    {
+        self.shutdown();
+
+        self.notify(EventMessage::Close(self.token));
     }
 
     pub fn handle_ping(&mut self, ping_frame: WebSocketFrame)
@@ -296,6 +838,7 @@ impl Client {
 
                 self.ping_sent = None;
                 if payload == self.ping_payload {
+                    self.missed_pongs = 0;
                     println!("Pong received, round trip time: {}", rtt);
                 } else {
                     println!("Pong received (but with the wrong payload), round trip time: {}", rtt);
@@ -304,16 +847,34 @@ impl Client {
         };
     }
 
-    pub fn handle_text_frame(&mut self, payload: String)
-    {
-        if self.state < ClientState::Running {
-            // Do nothing if not yet setup
-            return;
+    /// Whether the websocket handshake has completed and the close
+    /// handshake has not begun, so that frames may be exchanged with this
+    /// client
+    pub fn is_running(&self) -> bool {
+        self.state == ClientState::Running || self.state == ClientState::RunningAndWriting
+    }
+
+    /// The number of HTTP responses written so far, while the connection is
+    /// still speaking HTTP; None once it has become a websocket or closed
+    pub fn http_responses_sent(&self) -> Option<u64> {
+        match self.state {
+            ClientState::New | ClientState::AwaitingHandshake |
+            ClientState::HandshakeResponse | ClientState::HttpResponse => Some(self.responses_sent),
+            _ => None,
         }
+    }
 
-        println!("Text received: {}", payload);
+    /// Queue a frame that the server has already encoded, so that it can
+    /// share those bytes across many clients.  If this client's extensions
+    /// change the frame, it has to be encoded afresh.
+    pub fn send_shared_frame(&mut self, frame: &WebSocketFrame, encoded: &[u8])
+    {
+        match self.encode_frame(frame) {
+            Some(frame) => frame.write(&mut self.outgoing).unwrap(),
+            None => self.outgoing.extend_from_slice(encoded),
+        }
 
-        self.send_text_frame(payload);
+        self.state = ClientState::RunningAndWriting;
     }
 
     pub fn handle_binary_frame(&mut self, payload: Vec<u8>)
@@ -325,7 +886,27 @@ impl Client {
 
         println!("Binary frame received");
 
-        self.sender.send(EventMessage::ReArm(self.token)).unwrap();
+        self.notify(EventMessage::ReArm(self.token));
+    }
+
+    /// Called periodically by the server.  Pings the client, unless too
+    /// many pings have gone unanswered, in which case it returns false and
+    /// the connection should be closed.
+    pub fn heartbeat(&mut self, max_missed_pongs: u32) -> bool
+    {
+        if self.ping_sent.is_some() {
+            self.missed_pongs += 1;
+
+            if self.missed_pongs >= max_missed_pongs {
+                println!("Client {:?} missed {} pongs", self.token, self.missed_pongs);
+                return false;
+            }
+        }
+
+        self.pings_sent += 1;
+        let payload = format!("{}", self.pings_sent).into_bytes();
+        self.send_ping(payload);
+        true
     }
 
     pub fn send_ping(&mut self, payload: Vec<u8>)
@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+use mio::{EventLoop,Token};
+use handler::EventHandler;
+use server::Server;
+
+/// A command handler is given the server, the client that issued the
+/// command and the command's (already validated) arguments
+pub type CommandHandler = fn(&mut Server, &mut EventLoop<EventHandler>, Token, &[&str]);
+
+pub struct Command {
+    /// Name without the leading slash
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub min_args: usize,
+    pub max_args: usize,
+    /// If true, the last argument takes the rest of the line, spaces and all
+    pub trailing: bool,
+    pub handler: CommandHandler,
+}
+
+impl Command {
+    /// Split the text following the command name into arguments, checking
+    /// them against this command's argument counts
+    pub fn parse_args<'a>(&self, text: &'a str) -> Result<Vec<&'a str>, String> {
+        let mut args: Vec<&'a str> = Vec::new();
+        let mut rest = text.trim();
+
+        while !rest.is_empty() {
+            if self.trailing && args.len() + 1 == self.max_args {
+                args.push(rest);
+                break;
+            }
+
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            args.push(&rest[..end]);
+            rest = rest[end..].trim_start();
+        }
+
+        if args.len() < self.min_args || args.len() > self.max_args {
+            return Err(format!("Usage: {}", self.usage));
+        }
+
+        Ok(args)
+    }
+}
+
+/// Slash commands, looked up by name.  Commands are registered here rather
+/// than matched in the frame handling code, so new ones can be added freely.
+pub struct CommandRegistry {
+    commands: BTreeMap<&'static str, Command>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> CommandRegistry {
+        CommandRegistry {
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// A registry holding the built-in commands
+    pub fn with_builtins() -> CommandRegistry {
+        let mut registry = CommandRegistry::new();
+
+        registry.register(Command {
+            name: "nick", usage: "/nick <name>",
+            help: "Set or change your nickname",
+            min_args: 1, max_args: 1, trailing: false,
+            handler: cmd_nick,
+        });
+        registry.register(Command {
+            name: "join", usage: "/join <room>",
+            help: "Join a room, creating it if needed, and make it your current room",
+            min_args: 1, max_args: 1, trailing: false,
+            handler: cmd_join,
+        });
+        registry.register(Command {
+            name: "part", usage: "/part [room]",
+            help: "Leave a room (by default, your current room)",
+            min_args: 0, max_args: 1, trailing: false,
+            handler: cmd_part,
+        });
+        registry.register(Command {
+            name: "leave", usage: "/leave [room]",
+            help: "Same as /part",
+            min_args: 0, max_args: 1, trailing: false,
+            handler: cmd_part,
+        });
+        registry.register(Command {
+            name: "me", usage: "/me <action>",
+            help: "Describe an action to your current room",
+            min_args: 1, max_args: 1, trailing: true,
+            handler: cmd_me,
+        });
+        registry.register(Command {
+            name: "msg", usage: "/msg <nick> <text>",
+            help: "Send a private message to another user",
+            min_args: 2, max_args: 2, trailing: true,
+            handler: cmd_msg,
+        });
+        registry.register(Command {
+            name: "who", usage: "/who [room]",
+            help: "List the members of a room (by default, your current room)",
+            min_args: 0, max_args: 1, trailing: false,
+            handler: cmd_who,
+        });
+        registry.register(Command {
+            name: "online", usage: "/online",
+            help: "List everyone who is online",
+            min_args: 0, max_args: 0, trailing: false,
+            handler: cmd_online,
+        });
+        registry.register(Command {
+            name: "rooms", usage: "/rooms",
+            help: "List rooms and their member counts",
+            min_args: 0, max_args: 0, trailing: false,
+            handler: cmd_rooms,
+        });
+        registry.register(Command {
+            name: "register", usage: "/register <password>",
+            help: "Register your nickname as an account, so only you can use it",
+            min_args: 1, max_args: 1, trailing: true,
+            handler: cmd_register,
+        });
+        registry.register(Command {
+            name: "login", usage: "/login <nick> <password>",
+            help: "Log in to your account, taking its nickname",
+            min_args: 2, max_args: 2, trailing: true,
+            handler: cmd_login,
+        });
+        registry.register(Command {
+            name: "passwd", usage: "/passwd <old password> <new password>",
+            help: "Change your account's password",
+            min_args: 2, max_args: 2, trailing: true,
+            handler: cmd_passwd,
+        });
+        registry.register(Command {
+            name: "help", usage: "/help [command]",
+            help: "List commands, or describe one",
+            min_args: 0, max_args: 1, trailing: false,
+            handler: cmd_help,
+        });
+
+        registry
+    }
+
+    /// Register a command, replacing any existing command with that name
+    pub fn register(&mut self, command: Command) {
+        self.commands.insert(command.name, command);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Command> {
+        self.commands.get(name)
+    }
+
+    /// One line of help text per command
+    pub fn help_lines(&self) -> Vec<String> {
+        self.commands.values()
+            .map(|command| format!("{} - {}", command.usage, command.help))
+            .collect()
+    }
+}
+
+/// Split a line such as "/msg bob hi there" into the command name ("msg")
+/// and the text following it ("bob hi there").  Returns None if the line is
+/// not a command.
+pub fn split_command(line: &str) -> Option<(&str, &str)> {
+    if !line.starts_with('/') {
+        return None;
+    }
+
+    let line = &line[1..];
+    let end = line.find(char::is_whitespace).unwrap_or(line.len());
+    Some((&line[..end], &line[end..]))
+}
+
+fn cmd_nick(server: &mut Server, event_loop: &mut EventLoop<EventHandler>,
+            client_token: Token, args: &[&str])
+{
+    server.set_nick(event_loop, client_token, args[0]);
+}
+
+fn cmd_join(server: &mut Server, event_loop: &mut EventLoop<EventHandler>,
+            client_token: Token, args: &[&str])
+{
+    server.join_room(event_loop, client_token, args[0], None);
+}
+
+fn cmd_part(server: &mut Server, event_loop: &mut EventLoop<EventHandler>,
+            client_token: Token, args: &[&str])
+{
+    match args.first() {
+        Some(room) => server.leave_room(event_loop, client_token, room),
+        None => server.leave_current_room(event_loop, client_token),
+    }
+}
+
+fn cmd_me(server: &mut Server, event_loop: &mut EventLoop<EventHandler>,
+          client_token: Token, args: &[&str])
+{
+    server.send_room_message(event_loop, client_token, "emote", None, args[0]);
+}
+
+fn cmd_msg(server: &mut Server, event_loop: &mut EventLoop<EventHandler>,
+           client_token: Token, args: &[&str])
+{
+    server.send_private_message(event_loop, client_token, args[0], args[1]);
+}
+
+fn cmd_who(server: &mut Server, event_loop: &mut EventLoop<EventHandler>,
+           client_token: Token, args: &[&str])
+{
+    server.list_members(event_loop, client_token, args.first().map(|room| *room));
+}
+
+fn cmd_register(server: &mut Server, event_loop: &mut EventLoop<EventHandler>,
+                client_token: Token, args: &[&str])
+{
+    server.register_account(event_loop, client_token, args[0]);
+}
+
+fn cmd_login(server: &mut Server, event_loop: &mut EventLoop<EventHandler>,
+             client_token: Token, args: &[&str])
+{
+    server.login(event_loop, client_token, args[0], args[1]);
+}
+
+fn cmd_passwd(server: &mut Server, event_loop: &mut EventLoop<EventHandler>,
+              client_token: Token, args: &[&str])
+{
+    server.change_password(event_loop, client_token, args[0], args[1]);
+}
+
+fn cmd_online(server: &mut Server, event_loop: &mut EventLoop<EventHandler>,
+              client_token: Token, _args: &[&str])
+{
+    server.list_online(event_loop, client_token);
+}
+
+fn cmd_rooms(server: &mut Server, event_loop: &mut EventLoop<EventHandler>,
+             client_token: Token, _args: &[&str])
+{
+    server.list_rooms(event_loop, client_token);
+}
+
+fn cmd_help(server: &mut Server, event_loop: &mut EventLoop<EventHandler>,
+            client_token: Token, args: &[&str])
+{
+    let text = match args.first() {
+        None => {
+            let mut lines = vec!["Commands:".to_owned()];
+            lines.extend(server.commands().help_lines());
+            lines.join("\n")
+        },
+        Some(name) => {
+            let name = name.trim_start_matches('/');
+            match server.commands().get(name) {
+                Some(command) => format!("{} - {}", command.usage, command.help),
+                None => {
+                    server.send_error(event_loop, client_token, "unknown_command",
+                                      &format!("Unknown command: /{}", name));
+                    return;
+                },
+            }
+        },
+    };
+
+    server.send_notice(event_loop, client_token, &text);
+}
@@ -0,0 +1,119 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use deflate::{DeflateConfig,DeflateFactory};
+use extension::ExtensionFactory;
+use route::RouteRegistry;
+
+/// Server settings.  Start from `Config::default()` and change what you need.
+pub struct Config {
+    /// Address to listen on
+    pub address: SocketAddr,
+
+    /// Whether a client's room messages are sent back to it as well
+    pub echo_to_sender: bool,
+
+    /// Directory holding the per-room message logs
+    pub history_dir: PathBuf,
+
+    /// How many recent messages are replayed to a client joining a room.
+    /// This many messages per room are also kept in memory.
+    pub history_replay: usize,
+
+    /// How often the room logs are synced to disk, in milliseconds.  A
+    /// crash of the machine may lose the messages sent since.
+    pub history_sync_interval_ms: u64,
+
+    /// How often every client is pinged, in milliseconds
+    pub heartbeat_interval_ms: u64,
+
+    /// How many pings in a row a client may leave unanswered before it is
+    /// disconnected
+    pub max_missed_pongs: u32,
+
+    /// How long to wait for a client to finish the close handshake before
+    /// dropping the connection anyway, in milliseconds
+    pub close_timeout_ms: u64,
+
+    /// How long a connection may take to send a complete HTTP request, or
+    /// sit idle between kept alive requests, before it is dropped, in
+    /// milliseconds
+    pub request_timeout_ms: u64,
+
+    /// The largest frame payload a client may send, in bytes.  Checked
+    /// before the payload is buffered.
+    pub max_frame_size: usize,
+
+    /// The largest message a client may send, in bytes, after reassembling
+    /// fragments and decompressing
+    pub max_message_size: usize,
+
+    /// The extensions (Sec-WebSocket-Extensions) we will negotiate.  By
+    /// default, just permessage-deflate.
+    pub extensions: Vec<Box<dyn ExtensionFactory>>,
+
+    /// Directory to serve plain HTTP requests from, or None for the
+    /// built-in web chat client
+    pub static_dir: Option<PathBuf>,
+
+    /// The origins whose pages may open a websocket, such as
+    /// "https://chat.example.com" or "https://*.example.com" (see
+    /// `origin::is_allowed`).  Empty (the default) allows only the same
+    /// origin, whose host is the request's Host header.  Handshakes
+    /// without an Origin header, which come from clients other than
+    /// browsers, are always allowed.
+    pub allowed_origins: Vec<String>,
+
+    /// The websocket services, by request path.  By default, chat on "/"
+    /// and "/chat", and an echo service on "/echo".
+    pub routes: RouteRegistry,
+
+    /// The secret that access tokens are signed with.  If set, every
+    /// websocket handshake must carry a valid token (see the `auth`
+    /// module); if None, no one is asked for one.
+    pub auth_secret: Option<Vec<u8>>,
+
+    /// The cookie an access token may be given in
+    pub auth_cookie: String,
+
+    /// File holding the local user accounts, or None (the default) for no
+    /// accounts:  /register, /login and /passwd are then refused
+    pub accounts_file: Option<PathBuf>,
+
+    /// How many wrong passwords in a row lock an account
+    pub max_login_failures: u32,
+
+    /// How long an account stays locked, in seconds
+    pub login_lockout_secs: u32,
+
+    /// Threads for hashing and checking passwords.  They have a pool of
+    /// their own, as each job takes a while and socket I/O must not wait.
+    pub password_threads: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            address: "127.0.0.1:10000".parse().unwrap(),
+            echo_to_sender: true,
+            history_dir: PathBuf::from("history"),
+            history_replay: 50,
+            history_sync_interval_ms: 1_000,
+            heartbeat_interval_ms: 30_000,
+            max_missed_pongs: 2,
+            close_timeout_ms: 5_000,
+            request_timeout_ms: 10_000,
+            max_frame_size: 1 << 20,
+            max_message_size: 1 << 20,
+            extensions: vec![Box::new(DeflateFactory::new(DeflateConfig::default()))],
+            static_dir: None,
+            allowed_origins: Vec::new(),
+            routes: RouteRegistry::with_builtins(),
+            auth_secret: None,
+            auth_cookie: "access_token".to_owned(),
+            accounts_file: None,
+            max_login_failures: 5,
+            login_lockout_secs: 300,
+            password_threads: 2,
+        }
+    }
+}
@@ -0,0 +1,438 @@
+//! The permessage-deflate extension (RFC 7692)
+
+use flate2::{Compress, Compression, Decompress, Flush};
+use std::fmt;
+use extension::{Extension, ExtensionError, ExtensionFactory, ExtensionParams};
+use websocket_frame::{WebSocketFrame, OpCode, CloseCode, RSV1};
+
+pub const EXTENSION_NAME: &'static str = "permessage-deflate";
+
+// A message compressed with a sync flush ends with these bytes, which are
+// left off on the wire
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// The deflate implementation only works with the largest (32K) window
+const MAX_WINDOW_BITS: u8 = 15;
+
+/// How the server negotiates and uses permessage-deflate
+#[derive(Debug,Clone)]
+pub struct DeflateConfig {
+    /// Messages shorter than this many bytes are sent uncompressed
+    pub threshold: usize,
+
+    pub level: Compression,
+
+    /// Start every outgoing message with a fresh compression context,
+    /// trading compression ratio for memory
+    pub server_no_context_takeover: bool,
+
+    /// Ask clients to do the same for the messages they send
+    pub client_no_context_takeover: bool,
+
+    /// Ask clients to use at most this window size (8 to 15), if they
+    /// support the parameter
+    pub client_max_window_bits: Option<u8>,
+}
+
+impl Default for DeflateConfig {
+    fn default() -> DeflateConfig {
+        DeflateConfig {
+            threshold: 64,
+            level: Compression::Default,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            client_max_window_bits: None,
+        }
+    }
+}
+
+/// The parameters agreed with a client
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct DeflateParams {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: Option<u8>,
+    pub client_max_window_bits: Option<u8>,
+}
+
+impl DeflateParams {
+    /// Accept the first offer from the client that we can honour.  Each
+    /// offer is the parameter list of one permessage-deflate entry in the
+    /// client's Sec-WebSocket-Extensions header.
+    pub fn negotiate(offers: &[ExtensionParams], config: &DeflateConfig)
+                     -> Option<DeflateParams>
+    {
+        offers.iter().filter_map(|offer| Self::accept(offer, config)).next()
+    }
+
+    fn accept(offer: &[(String, Option<String>)], config: &DeflateConfig)
+              -> Option<DeflateParams>
+    {
+        let mut params = DeflateParams {
+            server_no_context_takeover: config.server_no_context_takeover,
+            client_no_context_takeover: config.client_no_context_takeover,
+            server_max_window_bits: None,
+            client_max_window_bits: None,
+        };
+
+        let mut seen: Vec<&str> = Vec::new();
+
+        for &(ref name, ref value) in offer.iter() {
+            // Each parameter may only be given once
+            if seen.contains(&&**name) {
+                return None;
+            }
+            seen.push(name);
+
+            match (&**name, value.as_ref().map(|v| &**v)) {
+                ("server_no_context_takeover", None) => {
+                    params.server_no_context_takeover = true;
+                },
+                ("client_no_context_takeover", None) => {
+                    params.client_no_context_takeover = true;
+                },
+                ("server_max_window_bits", Some(bits)) => {
+                    match parse_window_bits(bits) {
+                        // We cannot compress with a smaller window
+                        Some(MAX_WINDOW_BITS) => params.server_max_window_bits = Some(MAX_WINDOW_BITS),
+                        _ => return None,
+                    }
+                },
+                ("client_max_window_bits", None) => {
+                    params.client_max_window_bits = config.client_max_window_bits;
+                },
+                ("client_max_window_bits", Some(bits)) => {
+                    let bits = match parse_window_bits(bits) {
+                        None => return None,
+                        Some(bits) => bits,
+                    };
+                    // We decompress with the largest window, which copes with
+                    // any window the client uses.  Only ask for less.
+                    params.client_max_window_bits = match config.client_max_window_bits {
+                        Some(ours) if ours < bits => Some(ours),
+                        _ => None,
+                    };
+                },
+                _ => return None,
+            }
+        }
+
+        Some(params)
+    }
+
+    /// The extension as it appears in our Sec-WebSocket-Extensions header
+    pub fn response(&self) -> String {
+        let mut response = EXTENSION_NAME.to_owned();
+
+        if self.server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            response.push_str("; client_no_context_takeover");
+        }
+        if let Some(bits) = self.server_max_window_bits {
+            response.push_str(&format!("; server_max_window_bits={}", bits));
+        }
+        if let Some(bits) = self.client_max_window_bits {
+            response.push_str(&format!("; client_max_window_bits={}", bits));
+        }
+
+        response
+    }
+}
+
+fn parse_window_bits(value: &str) -> Option<u8> {
+    match value.trim_matches('"').parse::<u8>() {
+        Ok(bits) if bits >= 8 && bits <= MAX_WINDOW_BITS => Some(bits),
+        _ => None,
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum InflateError {
+    /// The message inflated to more than the size limit
+    TooBig,
+    /// The payload was not valid deflate data
+    Invalid,
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InflateError::TooBig => write!(f, "message too big"),
+            InflateError::Invalid => write!(f, "invalid compressed data"),
+        }
+    }
+}
+
+/// Offers permessage-deflate to new clients
+pub struct DeflateFactory {
+    config: DeflateConfig,
+}
+
+impl DeflateFactory {
+    pub fn new(config: DeflateConfig) -> DeflateFactory {
+        DeflateFactory {
+            config: config,
+        }
+    }
+}
+
+impl ExtensionFactory for DeflateFactory {
+    fn name(&self) -> &str {
+        EXTENSION_NAME
+    }
+
+    fn negotiate(&self, offers: &[ExtensionParams]) -> Option<Box<dyn Extension>> {
+        DeflateParams::negotiate(offers, &self.config).map(|params| {
+            Box::new(PerMessageDeflate::new(params, &self.config)) as Box<dyn Extension>
+        })
+    }
+}
+
+/// The compression state of one connection
+pub struct PerMessageDeflate {
+    params: DeflateParams,
+    threshold: usize,
+    compress: Compress,
+    decompress: Decompress,
+}
+
+impl PerMessageDeflate {
+    pub fn new(params: DeflateParams, config: &DeflateConfig) -> PerMessageDeflate {
+        PerMessageDeflate {
+            params: params,
+            threshold: config.threshold,
+            compress: Compress::new(config.level, false),
+            decompress: Decompress::new(false),
+        }
+    }
+
+    /// Whether an outgoing message of this size is worth compressing
+    pub fn should_compress(&self, len: usize) -> bool {
+        len >= self.threshold
+    }
+
+    /// Compress the payload of an outgoing message
+    pub fn compress(&mut self, payload: &[u8]) -> Vec<u8> {
+        let mut output: Vec<u8> = Vec::with_capacity(payload.len() / 2 + 64);
+        let start = self.compress.total_in();
+
+        loop {
+            if output.len() == output.capacity() {
+                let more = output.capacity();
+                output.reserve(more);
+            }
+
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress.compress_vec(&payload[consumed..], &mut output, Flush::Sync);
+
+            // The flush is complete once all input is taken and there was
+            // room to spare for output
+            let consumed = (self.compress.total_in() - start) as usize;
+            if consumed == payload.len() && output.len() < output.capacity() {
+                break;
+            }
+        }
+
+        if output.ends_with(&DEFLATE_TRAILER) {
+            let len = output.len() - DEFLATE_TRAILER.len();
+            output.truncate(len);
+        }
+
+        if self.params.server_no_context_takeover {
+            self.compress.reset();
+        }
+
+        output
+    }
+
+    /// Decompress the payload of an incoming message, giving up if it
+    /// inflates to more than `max_size` bytes
+    pub fn decompress(&mut self, payload: &[u8], max_size: usize) -> Result<Vec<u8>, InflateError> {
+        let mut input = payload.to_vec();
+        input.extend_from_slice(&DEFLATE_TRAILER);
+
+        let mut output: Vec<u8> = Vec::with_capacity(payload.len() * 2 + 64);
+        let start = self.decompress.total_in();
+
+        loop {
+            if output.len() == output.capacity() {
+                if output.len() >= max_size {
+                    return Err(InflateError::TooBig);
+                }
+                let more = output.capacity();
+                output.reserve(more);
+            }
+
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let produced = output.len();
+            try!(self.decompress.decompress_vec(&input[consumed..], &mut output, Flush::Sync)
+                 .map_err(|_| InflateError::Invalid));
+
+            let now_consumed = (self.decompress.total_in() - start) as usize;
+            if now_consumed == input.len() && output.len() < output.capacity() {
+                break;
+            }
+            if now_consumed == consumed && output.len() == produced
+                && output.len() < output.capacity()
+            {
+                // No progress, such as after the end of the deflate stream
+                return Err(InflateError::Invalid);
+            }
+        }
+
+        if output.len() > max_size {
+            return Err(InflateError::TooBig);
+        }
+
+        if self.params.client_no_context_takeover {
+            self.decompress.reset(false);
+        }
+
+        Ok(output)
+    }
+}
+
+impl Extension for PerMessageDeflate {
+    fn name(&self) -> &str {
+        EXTENSION_NAME
+    }
+
+    fn rsv_bits(&self) -> u8 {
+        RSV1
+    }
+
+    fn response(&self) -> String {
+        self.params.response()
+    }
+
+    fn encode(&mut self, frame: &WebSocketFrame) -> Option<WebSocketFrame> {
+        if frame.get_opcode() == OpCode::Continuation || frame.rsv_bits() & RSV1 != 0
+            || !self.should_compress(frame.payload.len())
+        {
+            return None;
+        }
+
+        let mut compressed = WebSocketFrame::data(frame.get_opcode(), self.compress(&frame.payload));
+        compressed.set_rsv_bits(frame.rsv_bits() | RSV1);
+        Some(compressed)
+    }
+
+    fn decode(&mut self, frame: WebSocketFrame, max_size: usize)
+              -> Result<WebSocketFrame, ExtensionError>
+    {
+        if frame.rsv_bits() & RSV1 == 0 {
+            return Ok(frame);
+        }
+
+        let payload = try!(self.decompress(&frame.payload, max_size).map_err(|e| {
+            ExtensionError {
+                code: match e {
+                    InflateError::TooBig => CloseCode::MessageTooBig,
+                    InflateError::Invalid => CloseCode::InvalidData,
+                },
+                reason: format!("{}", e),
+            }
+        }));
+
+        let mut decompressed = WebSocketFrame::data(frame.get_opcode(), payload);
+        decompressed.set_rsv_bits(frame.rsv_bits() & !RSV1);
+        Ok(decompressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeflateConfig, DeflateParams, InflateError, PerMessageDeflate};
+    use extension::ExtensionParams;
+
+    fn offer(params: &[(&str, Option<&str>)]) -> ExtensionParams {
+        params.iter()
+            .map(|&(name, value)| (name.to_owned(), value.map(|v| v.to_owned())))
+            .collect()
+    }
+
+    fn params(server_no_context_takeover: bool, client_no_context_takeover: bool) -> DeflateParams {
+        DeflateParams {
+            server_no_context_takeover: server_no_context_takeover,
+            client_no_context_takeover: client_no_context_takeover,
+            server_max_window_bits: None,
+            client_max_window_bits: None,
+        }
+    }
+
+    /// Compress with one end and decompress with the other, as a server
+    /// talking to itself
+    fn round_trip(params: DeflateParams) {
+        let config = DeflateConfig::default();
+        let mut sender = PerMessageDeflate::new(params.clone(), &config);
+        // The receiver decompresses what the "client" sends, so the server
+        // and client settings swap over
+        let mut receiver = PerMessageDeflate::new(DeflateParams {
+            server_no_context_takeover: params.client_no_context_takeover,
+            client_no_context_takeover: params.server_no_context_takeover,
+            .. params
+        }, &config);
+
+        let message = b"Hello, hello, hello, is there anybody in there?".to_vec();
+        for _ in 0..3 {
+            let compressed = sender.compress(&message);
+            assert!(!compressed.ends_with(&[0x00, 0x00, 0xff, 0xff]));
+            assert_eq!(receiver.decompress(&compressed, 1024).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn round_trips_with_context_takeover() {
+        round_trip(params(false, false));
+    }
+
+    #[test]
+    fn round_trips_without_context_takeover() {
+        round_trip(params(true, true));
+    }
+
+    #[test]
+    fn stops_decompressing_at_the_size_limit() {
+        let config = DeflateConfig::default();
+        let mut sender = PerMessageDeflate::new(params(false, false), &config);
+        let mut receiver = PerMessageDeflate::new(params(false, false), &config);
+
+        let compressed = sender.compress(&[b'a'; 100_000]);
+        assert!(compressed.len() < 1000);
+        assert_eq!(receiver.decompress(&compressed, 10_000), Err(InflateError::TooBig));
+    }
+
+    #[test]
+    fn accepts_a_plain_offer() {
+        let config = DeflateConfig::default();
+        assert_eq!(DeflateParams::negotiate(&[offer(&[])], &config), Some(params(false, false)));
+
+        let accepted = DeflateParams::negotiate(&[offer(&[("server_no_context_takeover", None),
+                                                          ("server_max_window_bits", Some("15"))])],
+                                                &config).unwrap();
+        assert!(accepted.server_no_context_takeover);
+        assert_eq!(accepted.server_max_window_bits, Some(15));
+        assert_eq!(accepted.response(),
+                   "permessage-deflate; server_no_context_takeover; server_max_window_bits=15");
+    }
+
+    #[test]
+    fn declines_offers_it_cannot_honour() {
+        let config = DeflateConfig::default();
+        let declined = [
+            offer(&[("server_max_window_bits", Some("10"))]),
+            offer(&[("client_no_context_takeover", None), ("client_no_context_takeover", None)]),
+            offer(&[("x-unknown", None)]),
+            offer(&[("client_max_window_bits", Some("16"))]),
+        ];
+        for declined in declined.iter() {
+            assert_eq!(DeflateParams::negotiate(&[declined.clone()], &config), None);
+        }
+
+        // A later offer is taken instead
+        let offers = [declined[0].clone(), offer(&[("client_no_context_takeover", None)])];
+        assert_eq!(DeflateParams::negotiate(&offers, &config), Some(params(false, true)));
+    }
+}
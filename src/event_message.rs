@@ -1,15 +1,28 @@
 
 use mio::Token;
-use websocket_frame::WebSocketFrame;
+use websocket_frame::{WebSocketFrame,CloseCode};
+use account::PasswordOutcome;
 
 pub enum EventMessage {
     /// Client has finished processing, and needs to be re-armed
     ReArm(Token),
 
+    /// Client has written an HTTP response on a kept alive connection, and
+    /// needs to be re-armed to read the next request
+    AwaitingRequest(Token),
+
+    /// Client stopped reading before it had handled everything, to give
+    /// the others a turn, and should read again
+    ReadMore(Token),
+
     /// Client has detected the remote has closed or errored, and should be
     /// shut down
     Close(Token),
 
+    /// Client broke the protocol, and should be closed with this status
+    /// code and reason
+    Fail(Token, CloseCode, String),
+
     /// Client received a polite close notification
     CloseRequest(Token, WebSocketFrame),
 
@@ -24,4 +37,7 @@ pub enum EventMessage {
 
     /// Client received these Bytes
     BinaryFrame(Token, Vec<u8>),
+
+    /// Password hashing for a client's command finished on the thread pool
+    Password(Token, PasswordOutcome),
 }
@@ -0,0 +1,259 @@
+//! Websocket extensions (RFC 6455, section 9).
+//!
+//! An extension is offered by the client in the Sec-WebSocket-Extensions
+//! header, accepted (or not) by an `ExtensionFactory` on the server, and
+//! then lives for the length of the connection.  Negotiated extensions form
+//! a pipeline:  outgoing messages pass through them in the order they were
+//! negotiated, on their way to `WebSocketFrame::write`, and incoming
+//! messages pass through them in reverse, after `WebSocketFrame::parse` (or
+//! `read`) and reassembly.
+//!
+//! Extensions see whole messages, each as a single final data frame.
+//! Control frames are not passed through them, and the reserved bits of a
+//! fragmented message are those of its first frame.
+
+use std::fmt;
+use websocket_frame::{WebSocketFrame, CloseCode};
+
+/// The parameters of one extension offer, in the order given
+pub type ExtensionParams = Vec<(String, Option<String>)>;
+
+/// Why an extension could not decode an incoming message
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtensionError {
+    /// The status code to close the connection with
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+impl fmt::Display for ExtensionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+/// Negotiates an extension with each new client
+pub trait ExtensionFactory: Send + Sync {
+    /// The extension token, as it appears in Sec-WebSocket-Extensions
+    fn name(&self) -> &str;
+
+    /// Accept the first of the client's offers of this extension that we
+    /// can honour, making the extension for this connection
+    fn negotiate(&self, offers: &[ExtensionParams]) -> Option<Box<dyn Extension>>;
+}
+
+/// An extension in use on one connection
+pub trait Extension: Send {
+    fn name(&self) -> &str;
+
+    /// The reserved bits (`RSV1` and so on) that this extension uses.  No
+    /// two extensions on a connection may use the same bit.
+    fn rsv_bits(&self) -> u8;
+
+    /// This extension as it appears in our Sec-WebSocket-Extensions header,
+    /// with the parameters agreed
+    fn response(&self) -> String;
+
+    /// Transform an outgoing message, returning None to send it unchanged
+    fn encode(&mut self, frame: &WebSocketFrame) -> Option<WebSocketFrame>;
+
+    /// Transform an incoming message.  The result may be no longer than
+    /// `max_size` bytes.
+    fn decode(&mut self, frame: WebSocketFrame, max_size: usize)
+              -> Result<WebSocketFrame, ExtensionError>;
+}
+
+/// The extensions negotiated for one connection, in pipeline order
+pub struct Extensions {
+    list: Vec<Box<dyn Extension>>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Extensions {
+            list: Vec::new(),
+        }
+    }
+
+    /// Negotiate extensions from the client's Sec-WebSocket-Extensions
+    /// header, taking them in the order the client offered them.  An
+    /// extension that wants a reserved bit already claimed by an earlier
+    /// one is refused.
+    pub fn negotiate(header: &str, factories: &[Box<dyn ExtensionFactory>]) -> Extensions {
+        let offers = parse_extensions(header);
+
+        let mut names: Vec<&str> = Vec::new();
+        for &(ref name, _) in offers.iter() {
+            if !names.contains(&&**name) {
+                names.push(name);
+            }
+        }
+
+        let mut extensions = Extensions::new();
+
+        for name in names.into_iter() {
+            let factory = match factories.iter().find(|factory| factory.name() == name) {
+                None => continue,
+                Some(factory) => factory,
+            };
+
+            let params: Vec<ExtensionParams> = offers.iter()
+                .filter(|&&(ref offered, _)| offered == name)
+                .map(|&(_, ref params)| params.clone())
+                .collect();
+
+            let extension = match factory.negotiate(&params) {
+                None => continue,
+                Some(extension) => extension,
+            };
+
+            if extension.rsv_bits() & extensions.rsv_bits() != 0 {
+                println!("Not negotiating extension {}:  its reserved bits are taken",
+                         extension.name());
+                continue;
+            }
+
+            extensions.list.push(extension);
+        }
+
+        extensions
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// The reserved bits used by all the extensions
+    pub fn rsv_bits(&self) -> u8 {
+        self.list.iter().fold(0, |bits, extension| bits | extension.rsv_bits())
+    }
+
+    /// The value of our Sec-WebSocket-Extensions header, if any extensions
+    /// were negotiated
+    pub fn response(&self) -> Option<String> {
+        if self.list.is_empty() {
+            return None;
+        }
+
+        let responses: Vec<String> = self.list.iter().map(|extension| extension.response()).collect();
+        Some(responses.join(", "))
+    }
+
+    /// Pass an outgoing message through each extension in turn, returning
+    /// None if none of them changed it
+    pub fn encode(&mut self, frame: &WebSocketFrame) -> Option<WebSocketFrame> {
+        let mut encoded: Option<WebSocketFrame> = None;
+
+        for extension in self.list.iter_mut() {
+            let next = extension.encode(encoded.as_ref().unwrap_or(frame));
+            if next.is_some() {
+                encoded = next;
+            }
+        }
+
+        encoded
+    }
+
+    /// Pass an incoming message back through each extension, last first
+    pub fn decode(&mut self, frame: WebSocketFrame, max_size: usize)
+                  -> Result<WebSocketFrame, ExtensionError>
+    {
+        let mut frame = frame;
+
+        for extension in self.list.iter_mut().rev() {
+            frame = try!(extension.decode(frame, max_size));
+        }
+
+        Ok(frame)
+    }
+}
+
+/// Split a Sec-WebSocket-Extensions header value into its extensions, each
+/// with its list of parameters
+pub fn parse_extensions(header: &str) -> Vec<(String, ExtensionParams)> {
+    header.split(',')
+        .filter_map(|extension| {
+            let mut parts = extension.split(';').map(|part| part.trim());
+            let name = match parts.next() {
+                Some(name) if !name.is_empty() => name.to_owned(),
+                _ => return None,
+            };
+            let params = parts
+                .filter(|param| !param.is_empty())
+                .map(|param| match param.find('=') {
+                    None => (param.to_owned(), None),
+                    Some(eq) => (param[..eq].trim().to_owned(),
+                                 Some(param[eq + 1..].trim().to_owned())),
+                })
+                .collect();
+            Some((name, params))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Extension, ExtensionError, ExtensionFactory, ExtensionParams, Extensions};
+    use deflate::{DeflateConfig, DeflateFactory};
+    use websocket_frame::{WebSocketFrame, RSV1};
+
+    /// An extension that, like permessage-deflate, claims RSV1
+    struct Rsv1Factory;
+    struct Rsv1;
+
+    impl ExtensionFactory for Rsv1Factory {
+        fn name(&self) -> &str {
+            "x-rsv1"
+        }
+
+        fn negotiate(&self, _offers: &[ExtensionParams]) -> Option<Box<dyn Extension>> {
+            Some(Box::new(Rsv1))
+        }
+    }
+
+    impl Extension for Rsv1 {
+        fn name(&self) -> &str {
+            "x-rsv1"
+        }
+
+        fn rsv_bits(&self) -> u8 {
+            RSV1
+        }
+
+        fn response(&self) -> String {
+            "x-rsv1".to_owned()
+        }
+
+        fn encode(&mut self, _frame: &WebSocketFrame) -> Option<WebSocketFrame> {
+            None
+        }
+
+        fn decode(&mut self, frame: WebSocketFrame, _max_size: usize)
+                  -> Result<WebSocketFrame, ExtensionError>
+        {
+            Ok(frame)
+        }
+    }
+
+    fn factories() -> Vec<Box<dyn ExtensionFactory>> {
+        vec![Box::new(DeflateFactory::new(DeflateConfig::default())), Box::new(Rsv1Factory)]
+    }
+
+    #[test]
+    fn negotiates_nothing_without_offers() {
+        let extensions = Extensions::negotiate("", &factories());
+        assert!(extensions.is_empty());
+        assert_eq!(extensions.response(), None);
+    }
+
+    #[test]
+    fn refuses_an_extension_whose_reserved_bit_is_taken() {
+        let extensions = Extensions::negotiate("permessage-deflate, x-rsv1", &factories());
+        assert_eq!(extensions.rsv_bits(), RSV1);
+        assert_eq!(extensions.response(), Some("permessage-deflate".to_owned()));
+
+        // The client's order decides which one gets the bit
+        let extensions = Extensions::negotiate("x-rsv1, permessage-deflate", &factories());
+        assert_eq!(extensions.response(), Some("x-rsv1".to_owned()));
+    }
+}
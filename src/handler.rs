@@ -3,6 +3,23 @@ use mio::{Handler,EventLoop,Token,EventSet};
 use server::{Server,LISTENER_FD};
 use event_message::EventMessage;
 
+/// Timers scheduled on the event loop
+pub enum TimerEvent {
+    /// Time to ping every client, and evict those that stopped answering
+    Heartbeat,
+
+    /// The client has taken too long to finish the close handshake
+    CloseTimeout(Token),
+
+    /// The client has taken too long to send a complete HTTP request, or
+    /// been idle too long between requests.  The count of responses it had
+    /// been sent tells whether the timeout is for its current request.
+    RequestTimeout(Token, u64),
+
+    /// Time to sync the room logs written to since the last sync
+    HistorySync,
+}
+
 pub struct EventHandler {
     server: Server,
 }
@@ -16,11 +33,13 @@ impl EventHandler {
 
     pub fn register_server(&mut self, event_loop: &mut EventLoop<EventHandler>) {
         self.server.register(event_loop);
+        self.server.schedule_heartbeat(event_loop);
+        self.server.schedule_history_sync(event_loop);
     }
 }
 
 impl Handler for EventHandler {
-    type Timeout = ();
+    type Timeout = TimerEvent;
     type Message = EventMessage;
 
     fn ready(&mut self, event_loop: &mut EventLoop<EventHandler>,
@@ -32,8 +51,10 @@ impl Handler for EventHandler {
             },
             // All other tokens must be clients
             client_token => {
-                if events.is_hup() {
-                    self.server.handle_client_close(client_token);
+                if events.is_hup() || events.is_error() {
+                    // The connection is dead, so it must not linger online
+                    self.server.handle_client_close(event_loop, client_token);
+                    return;
                 }
                 if events.is_writable() {
                     self.server.handle_client_write(event_loop, client_token);
@@ -52,11 +73,20 @@ impl Handler for EventHandler {
             EventMessage::ReArm(client_token) => {
                 self.server.handle_client_rearm(event_loop, client_token);
             },
+            EventMessage::AwaitingRequest(client_token) => {
+                self.server.handle_client_awaiting_request(event_loop, client_token);
+            },
+            EventMessage::ReadMore(client_token) => {
+                self.server.handle_client_read(event_loop, client_token);
+            },
             EventMessage::Close(client_token) => {
-                self.server.handle_client_close(client_token);
+                self.server.handle_client_close(event_loop, client_token);
+            },
+            EventMessage::Fail(client_token, status_code, reason) => {
+                self.server.close_client(event_loop, client_token, status_code, &reason);
             },
             EventMessage::CloseRequest(client_token, payload) => {
-                self.server.handle_client_close_request(client_token, payload);
+                self.server.handle_client_close_request(event_loop, client_token, payload);
             },
             EventMessage::Ping(client_token, payload) => {
                 self.server.handle_client_ping(client_token, payload);
@@ -65,11 +95,35 @@ impl Handler for EventHandler {
                 self.server.handle_client_pong(client_token, payload);
             },
             EventMessage::TextFrame(client_token, payload) => {
-                self.server.handle_client_text_frame(client_token, payload);
+                self.server.handle_client_text_frame(event_loop, client_token, payload);
             },
             EventMessage::BinaryFrame(client_token, payload) => {
                 self.server.handle_client_binary_frame(client_token, payload);
             },
+            EventMessage::Password(client_token, outcome) => {
+                self.server.handle_password_outcome(event_loop, client_token, outcome);
+            },
+        }
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<EventHandler>,
+               timeout: TimerEvent)
+    {
+        match timeout {
+            TimerEvent::Heartbeat => {
+                self.server.handle_heartbeat(event_loop);
+                self.server.schedule_heartbeat(event_loop);
+            },
+            TimerEvent::CloseTimeout(client_token) => {
+                self.server.handle_close_timeout(event_loop, client_token);
+            },
+            TimerEvent::RequestTimeout(client_token, responses_sent) => {
+                self.server.handle_request_timeout(event_loop, client_token, responses_sent);
+            },
+            TimerEvent::HistorySync => {
+                self.server.handle_history_sync();
+                self.server.schedule_history_sync(event_loop);
+            },
         }
     }
 }
@@ -0,0 +1,522 @@
+use std::collections::{HashMap,VecDeque};
+use std::fs::{self,File,OpenOptions};
+use std::io::{self,Read,Write,Seek,SeekFrom,Cursor};
+use std::path::{Path,PathBuf};
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use protocol::Envelope;
+
+const LOG_EXTENSION: &'static str = "log";
+
+/// Logs damaged other than at the end are moved aside with this extension
+const DAMAGED_EXTENSION: &'static str = "damaged";
+
+// Each record is the payload length and an Adler-32 checksum of the payload
+// (both u32 big endian), followed by the payload itself
+const RECORD_HEADER_LEN: usize = 8;
+
+/// A room's open log, with enough of it in memory that replays rarely need
+/// to read the file
+struct RoomLog {
+    file: File,
+    /// The message id and file offset of every record, oldest first
+    index: Vec<(u64, u64)>,
+    /// The most recent records, oldest first
+    tail: VecDeque<String>,
+    /// Written to since the last sync
+    unsynced: bool,
+}
+
+/// An append-only log of the messages sent to each room, one file per room.
+///
+/// Records are written whole, but synced to disk in batches (see
+/// `take_unsynced`), so a crash of the machine may lose the last few
+/// messages.  If the server dies part way through a write, the truncated
+/// (or corrupt) trailing record is detected by its length or checksum and
+/// skipped; when that room's log is next opened, it is cut off.  A log
+/// damaged anywhere else is moved aside whole, and only the records before
+/// the damage are carried on with.
+///
+/// A room's log is read when the room is first used.  While the room is
+/// in use, the last `tail_len` messages are kept in memory, and older ones
+/// are found through an index of record offsets.  Once the room is empty
+/// the log is closed (see `close_room`), to be read again if it is reused.
+pub struct History {
+    dir: PathBuf,
+    logs: HashMap<String, RoomLog>,
+    /// Logs closed before their last writes were synced
+    closed_unsynced: Vec<File>,
+    tail_len: usize,
+    last_id: u64,
+}
+
+impl History {
+    /// Open (creating if necessary) the history directory, scanning the
+    /// existing logs for the highest message id.  `tail_len` messages per
+    /// room are kept in memory.
+    pub fn open(dir: &Path, tail_len: usize) -> io::Result<History> {
+        try!(fs::create_dir_all(dir));
+
+        let mut last_id = 0;
+
+        for entry in try!(fs::read_dir(dir)) {
+            let path = try!(entry).path();
+            if path.extension().and_then(|e| e.to_str()) != Some(LOG_EXTENSION) {
+                continue;
+            }
+
+            let (records, _) = try!(Self::read_records(&path, 0));
+            for &(_, ref record) in records.iter() {
+                if let Some(id) = Self::record_id(record) {
+                    if id > last_id {
+                        last_id = id;
+                    }
+                }
+            }
+        }
+
+        Ok(History {
+            dir: dir.to_owned(),
+            logs: HashMap::new(),
+            closed_unsynced: Vec::new(),
+            tail_len: tail_len,
+            last_id: last_id,
+        })
+    }
+
+    /// The highest message id found in the logs when they were opened
+    pub fn last_id(&self) -> u64 {
+        self.last_id
+    }
+
+    /// Append an encoded message envelope to a room's log
+    pub fn append(&mut self, room: &str, encoded: &str) -> io::Result<()> {
+        let payload = encoded.as_bytes();
+        let mut record: Vec<u8> = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        try!(record.write_u32::<BigEndian>(payload.len() as u32));
+        try!(record.write_u32::<BigEndian>(adler32(payload)));
+        try!(record.write_all(payload));
+
+        if !self.logs.contains_key(room) {
+            let log = try!(self.load(room));
+            self.logs.insert(room.to_owned(), log);
+        }
+
+        let tail_len = self.tail_len;
+        let result = {
+            let log = self.logs.get_mut(room).unwrap();
+            log.file.seek(SeekFrom::End(0)).and_then(|offset| {
+                try!(log.file.write_all(&record));
+
+                // Ids only ever grow, so a record without one can share its
+                // predecessor's and the index stays sorted
+                let id = Self::record_id(encoded)
+                    .unwrap_or_else(|| log.index.last().map_or(0, |&(id, _)| id));
+                log.index.push((id, offset));
+                log.tail.push_back(encoded.to_owned());
+                if log.tail.len() > tail_len {
+                    log.tail.pop_front();
+                }
+                log.unsynced = true;
+                Ok(())
+            })
+        };
+
+        if result.is_err() {
+            // The record may be partly written.  Reloading the log will cut
+            // it off before anything else is appended.
+            self.logs.remove(room);
+        }
+
+        result
+    }
+
+    /// Encoded messages from a room's log, oldest first:  those with an id
+    /// greater than `since` if given, otherwise the last `limit` messages
+    pub fn replay(&mut self, room: &str, since: Option<u64>, limit: usize) -> io::Result<Vec<String>> {
+        if !self.logs.contains_key(room) {
+            if !self.path_for(room).exists() {
+                return Ok(Vec::new());
+            }
+            let log = try!(self.load(room));
+            self.logs.insert(room.to_owned(), log);
+        }
+
+        let path = self.path_for(room);
+        let log = self.logs.get(room).unwrap();
+
+        // The position in the index of the first record wanted
+        let first = match since {
+            Some(since) => match log.index.binary_search_by(|&(id, _)| id.cmp(&since)) {
+                // Several records may share an id; skip all of them
+                Ok(found) => found + log.index[found..].iter().take_while(|&&(id, _)| id == since).count(),
+                Err(insert_at) => insert_at,
+            },
+            None => log.index.len().saturating_sub(limit),
+        };
+        let wanted = log.index.len() - first;
+
+        if wanted <= log.tail.len() {
+            let skip = log.tail.len() - wanted;
+            return Ok(log.tail.iter().skip(skip).cloned().collect());
+        }
+
+        // Older than the tail, so read the file from the first one on
+        let (records, _) = try!(Self::read_records(&path, log.index[first].1));
+        Ok(records.into_iter().take(wanted).map(|(_, record)| record).collect())
+    }
+
+    /// Close a room's log and forget its index and tail, once nobody is in
+    /// the room
+    pub fn close_room(&mut self, room: &str) {
+        if let Some(log) = self.logs.remove(room) {
+            if log.unsynced {
+                self.closed_unsynced.push(log.file);
+            }
+        }
+    }
+
+    /// Take the logs written to since the last call, to be synced to disk.
+    /// Syncing is slow, so it is left to the caller to do elsewhere.
+    pub fn take_unsynced(&mut self) -> Vec<File> {
+        let mut files: Vec<File> = self.closed_unsynced.drain(..).collect();
+
+        for (room, log) in self.logs.iter_mut() {
+            if !log.unsynced {
+                continue;
+            }
+            match log.file.try_clone() {
+                Ok(file) => {
+                    files.push(file);
+                    log.unsynced = false;
+                },
+                Err(e) => println!("Failed to sync history for room {}: {}", room, e),
+            }
+        }
+
+        files
+    }
+
+    /// Room names may differ only in case, and some file systems ignore
+    /// case, so anything but lower case letters, digits and "-" is escaped
+    /// as "_" and two hex digits ("Lobby" is "_4cobby.log")
+    fn path_for(&self, room: &str) -> PathBuf {
+        let mut name = String::with_capacity(room.len());
+        for byte in room.bytes() {
+            match byte {
+                b'a'..=b'z' | b'0'..=b'9' | b'-' => name.push(byte as char),
+                _ => name.push_str(&format!("_{:02x}", byte)),
+            }
+        }
+
+        self.dir.join(format!("{}.{}", name, LOG_EXTENSION))
+    }
+
+    /// Read a room's log into memory and open it for appending, creating it
+    /// if necessary
+    fn load(&self, room: &str) -> io::Result<RoomLog> {
+        let path = self.path_for(room);
+
+        let mut index: Vec<(u64, u64)> = Vec::new();
+        let mut tail: VecDeque<String> = VecDeque::new();
+
+        if path.exists() {
+            let (records, valid_len) = try!(Self::read_records(&path, 0));
+
+            if try!(fs::metadata(&path)).len() > valid_len {
+                if try!(Self::is_torn_tail(&path, valid_len)) {
+                    // Cut off the partially written last record, so new
+                    // records are not appended after garbage
+                    println!("Truncating damaged history record in {}", path.display());
+                    let file = try!(OpenOptions::new().write(true).open(&path));
+                    try!(file.set_len(valid_len));
+                } else {
+                    // Something else damaged the file, and there may be good
+                    // records after the damage, so keep it for inspection and
+                    // carry on with a copy of the records before it
+                    let mut damaged = path.with_extension(DAMAGED_EXTENSION);
+                    let mut n = 1;
+                    while damaged.exists() {
+                        damaged = path.with_extension(format!("{}-{}", DAMAGED_EXTENSION, n));
+                        n += 1;
+                    }
+                    println!("Damaged history in {}; moved to {}", path.display(), damaged.display());
+                    try!(fs::rename(&path, &damaged));
+                    try!(fs::copy(&damaged, &path));
+                    let file = try!(OpenOptions::new().write(true).open(&path));
+                    try!(file.set_len(valid_len));
+                }
+            }
+
+            let skip = records.len().saturating_sub(self.tail_len);
+            for (i, (offset, record)) in records.into_iter().enumerate() {
+                let id = Self::record_id(&record)
+                    .unwrap_or_else(|| index.last().map_or(0, |&(id, _)| id));
+                index.push((id, offset));
+                if i >= skip {
+                    tail.push_back(record);
+                }
+            }
+        }
+
+        let file = try!(OpenOptions::new().append(true).create(true).open(&path));
+
+        Ok(RoomLog {
+            file: file,
+            index: index,
+            tail: tail,
+            unsynced: false,
+        })
+    }
+
+    /// Read the valid records of a log from `start` on, with their offsets,
+    /// and the length of the file that they cover.  Reading stops at the
+    /// first truncated or corrupt record.
+    fn read_records(path: &Path, start: u64) -> io::Result<(Vec<(u64, String)>, u64)> {
+        let mut file = try!(File::open(path));
+        try!(file.seek(SeekFrom::Start(start)));
+
+        let mut contents: Vec<u8> = Vec::new();
+        try!(file.read_to_end(&mut contents));
+
+        let (records, valid_len) = try!(Self::parse_records(&contents, start));
+
+        if (valid_len - start) < contents.len() as u64 {
+            println!("Skipping damaged history record at offset {} in {}",
+                     valid_len, path.display());
+        }
+
+        Ok((records, valid_len))
+    }
+
+    /// Whether the damage found at `offset` in a log is only a record cut
+    /// short by a crash:  the last thing in the file, and all of it
+    fn is_torn_tail(path: &Path, offset: u64) -> io::Result<bool> {
+        let mut file = try!(File::open(path));
+        try!(file.seek(SeekFrom::Start(offset)));
+
+        let mut rest: Vec<u8> = Vec::new();
+        try!(file.read_to_end(&mut rest));
+
+        if rest.len() < RECORD_HEADER_LEN {
+            return Ok(true);
+        }
+        let len = try!(Cursor::new(&rest[..4]).read_u32::<BigEndian>()) as usize;
+        Ok(RECORD_HEADER_LEN + len >= rest.len())
+    }
+
+    /// The valid records in `contents`, which was read from offset `start`
+    /// of a log
+    fn parse_records(contents: &[u8], start: u64) -> io::Result<(Vec<(u64, String)>, u64)> {
+        let mut records: Vec<(u64, String)> = Vec::new();
+        let mut offset: usize = 0;
+
+        while contents.len() - offset >= RECORD_HEADER_LEN {
+            let mut header = Cursor::new(&contents[offset..offset + RECORD_HEADER_LEN]);
+            let len = try!(header.read_u32::<BigEndian>()) as usize;
+            let checksum = try!(header.read_u32::<BigEndian>());
+
+            let payload_start = offset + RECORD_HEADER_LEN;
+            if contents.len() - payload_start < len {
+                break;
+            }
+
+            let payload = &contents[payload_start..payload_start + len];
+            if adler32(payload) != checksum {
+                break;
+            }
+
+            match String::from_utf8(payload.to_vec()) {
+                Ok(record) => records.push((start + offset as u64, record)),
+                Err(_) => break,
+            }
+
+            offset = payload_start + len;
+        }
+
+        Ok((records, start + offset as u64))
+    }
+
+    fn record_id(record: &str) -> Option<u64> {
+        Envelope::decode(record).ok().and_then(|envelope| envelope.id)
+    }
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    for byte in bytes.iter() {
+        a = (a + *byte as u32) % MOD_ADLER;
+        b = (b + a) % MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self,OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize,Ordering};
+    use protocol::Envelope;
+    use super::History;
+
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+    /// A fresh, empty directory for a test's logs
+    fn temp_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("chat-history-test-{}-{}",
+                                               ::std::process::id(),
+                                               NEXT_DIR.fetch_add(1, Ordering::SeqCst)));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn message(id: u64, body: &str) -> String {
+        let mut envelope = Envelope::new("message");
+        envelope.room = Some("lobby".to_owned());
+        envelope.id = Some(id);
+        envelope.body = Some(body.to_owned());
+        envelope.encode()
+    }
+
+    fn append_bytes(history: &History, room: &str, bytes: &[u8]) {
+        let mut file = OpenOptions::new().append(true).open(history.path_for(room)).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn replays_in_order() {
+        let dir = temp_dir();
+        let mut history = History::open(&dir, 10).unwrap();
+        for id in 1..4 {
+            history.append("lobby", &message(id, "hi")).unwrap();
+        }
+
+        assert_eq!(history.replay("lobby", None, 2).unwrap(),
+                   vec![message(2, "hi"), message(3, "hi")]);
+        assert_eq!(history.replay("lobby", Some(1), 10).unwrap(),
+                   vec![message(2, "hi"), message(3, "hi")]);
+        assert!(history.replay("nowhere", None, 10).unwrap().is_empty());
+
+        // Reopening finds the last id, and replays from the file
+        let mut history = History::open(&dir, 10).unwrap();
+        assert_eq!(history.last_id(), 3);
+        assert_eq!(history.replay("lobby", None, 10).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn replays_beyond_the_tail_from_the_file() {
+        let dir = temp_dir();
+        let mut history = History::open(&dir, 2).unwrap();
+        for id in 1..6 {
+            history.append("lobby", &message(id, "hi")).unwrap();
+        }
+
+        assert_eq!(history.replay("lobby", Some(2), 10).unwrap(),
+                   vec![message(3, "hi"), message(4, "hi"), message(5, "hi")]);
+        assert_eq!(history.replay("lobby", None, 4).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn skips_a_truncated_record() {
+        let dir = temp_dir();
+        let mut history = History::open(&dir, 10).unwrap();
+        history.append("lobby", &message(1, "kept")).unwrap();
+        let path = history.path_for("lobby");
+        let valid_len = fs::metadata(&path).unwrap().len();
+
+        // A header promising more payload than was written
+        append_bytes(&history, "lobby", &[0, 0, 0, 100, 0, 0, 0, 1, b'{']);
+
+        let (records, len) = History::read_records(&path, 0).unwrap();
+        assert_eq!(records, vec![(0, message(1, "kept"))]);
+        assert_eq!(len, valid_len);
+    }
+
+    #[test]
+    fn stops_at_a_corrupt_record() {
+        let dir = temp_dir();
+        let mut history = History::open(&dir, 10).unwrap();
+        history.append("lobby", &message(1, "kept")).unwrap();
+        history.append("lobby", &message(2, "damaged")).unwrap();
+        history.append("lobby", &message(3, "lost")).unwrap();
+        let path = history.path_for("lobby");
+
+        // Flip a byte in the middle record's payload
+        let (records, _) = History::read_records(&path, 0).unwrap();
+        let mut contents = fs::read(&path).unwrap();
+        contents[records[1].0 as usize + 12] ^= 0xff;
+        fs::write(&path, &contents).unwrap();
+
+        let (records, len) = History::read_records(&path, 0).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].1, message(1, "kept"));
+        assert!(len < contents.len() as u64);
+    }
+
+    #[test]
+    fn cuts_off_a_damaged_tail_before_appending() {
+        let dir = temp_dir();
+        {
+            let mut history = History::open(&dir, 10).unwrap();
+            history.append("lobby", &message(1, "kept")).unwrap();
+            append_bytes(&history, "lobby", &[0, 0]);
+        }
+
+        let mut history = History::open(&dir, 10).unwrap();
+        history.append("lobby", &message(2, "after")).unwrap();
+
+        let mut history = History::open(&dir, 10).unwrap();
+        assert_eq!(history.replay("lobby", None, 10).unwrap(),
+                   vec![message(1, "kept"), message(2, "after")]);
+    }
+
+    #[test]
+    fn moves_aside_a_log_damaged_in_the_middle() {
+        let dir = temp_dir();
+        let path = {
+            let mut history = History::open(&dir, 10).unwrap();
+            history.append("lobby", &message(1, "kept")).unwrap();
+            history.append("lobby", &message(2, "damaged")).unwrap();
+            history.append("lobby", &message(3, "after")).unwrap();
+            history.path_for("lobby")
+        };
+
+        let (records, _) = History::read_records(&path, 0).unwrap();
+        let mut contents = fs::read(&path).unwrap();
+        contents[records[1].0 as usize + 12] ^= 0xff;
+        fs::write(&path, &contents).unwrap();
+
+        let mut history = History::open(&dir, 10).unwrap();
+        assert_eq!(history.replay("lobby", None, 10).unwrap(), vec![message(1, "kept")]);
+
+        // Nothing is lost:  the damaged log is kept whole
+        assert_eq!(fs::read(path.with_extension("damaged")).unwrap(), contents);
+    }
+
+    #[test]
+    fn reloads_a_closed_room() {
+        let dir = temp_dir();
+        let mut history = History::open(&dir, 10).unwrap();
+        history.append("lobby", &message(1, "hi")).unwrap();
+
+        history.close_room("lobby");
+        assert!(history.logs.is_empty());
+        assert_eq!(history.take_unsynced().len(), 1);
+
+        history.append("lobby", &message(2, "again")).unwrap();
+        assert_eq!(history.replay("lobby", None, 10).unwrap(),
+                   vec![message(1, "hi"), message(2, "again")]);
+    }
+
+    #[test]
+    fn rooms_differing_in_case_have_separate_logs() {
+        let history = History::open(&temp_dir(), 10).unwrap();
+        assert!(history.path_for("Lobby") != history.path_for("lobby"));
+        assert!(history.path_for("Lobby").ends_with("_4cobby.log"));
+        assert!(history.path_for("#a_b").ends_with("_23a_5fb.log"));
+    }
+}
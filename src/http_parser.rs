@@ -1,28 +1,338 @@
 use http_muncher::{ParserHandler};
-use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std;
+use sha1;
+use rustc_serialize::base64::{ToBase64, STANDARD};
 
+/// Appended to the Sec-WebSocket-Key before hashing (RFC 6455, section 1.3)
+const WEBSOCKET_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
+/// The Sec-WebSocket-Accept value that answers a Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    let mut m = sha1::Sha1::new();
+    let mut rbuf = [0u8; 20];
+
+    m.update(key.as_bytes());
+    m.update(WEBSOCKET_GUID.as_bytes());
+
+    m.output(&mut rbuf);
+
+    rbuf.to_base64(STANDARD)
+}
+
+
+/// The most headers a request may have
+pub const MAX_HEADERS: usize = 64;
+
+/// The most bytes of header names and values a request may have
+pub const MAX_HEADERS_SIZE: usize = 8192;
+
+/// HTTP headers, looked up ignoring case.  A header may be given more than
+/// once; every value is kept, in order.
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, name: String, value: String) {
+        self.entries.push((name, value));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.iter().any(|&(ref n, _)| n.eq_ignore_ascii_case(name))
+    }
+
+    /// Every value of a header
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.entries.iter()
+            .filter(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref v)| v.trim())
+            .collect()
+    }
+
+    /// The value of a header that should appear once.  None if it is
+    /// missing or repeated.
+    pub fn get_one(&self, name: &str) -> Option<&str> {
+        let values = self.get_all(name);
+        if values.len() == 1 { Some(values[0]) } else { None }
+    }
+
+    /// Every value of a list header joined into one, as though it had been
+    /// sent once (RFC 7230, section 3.2.2)
+    pub fn get_joined(&self, name: &str) -> Option<String> {
+        let values = self.get_all(name);
+        if values.is_empty() { None } else { Some(values.join(", ")) }
+    }
+
+    /// The items of a comma separated list header, across all its values
+    pub fn tokens(&self, name: &str) -> Vec<&str> {
+        self.get_all(name).into_iter()
+            .flat_map(|value| value.split(','))
+            .map(|token| token.trim())
+            .filter(|token| !token.is_empty())
+            .collect()
+    }
+
+    /// Whether a list header holds this token, ignoring case (as in
+    /// "Connection: keep-alive, Upgrade")
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.tokens(name).iter().any(|t| t.eq_ignore_ascii_case(token))
+    }
+}
+
+/// What has been parsed of a request so far
+pub struct HttpRequest {
+    /// The request target, such as "/index.html"
+    pub url: String,
+    pub headers: Headers,
+    /// Set once the blank line ending the headers has been parsed
+    pub complete: bool,
+    /// Set if the request has more headers, or longer ones, than allowed
+    pub too_large: bool,
+}
+
+impl HttpRequest {
+    pub fn new() -> HttpRequest {
+        HttpRequest {
+            url: String::new(),
+            headers: Headers::new(),
+            complete: false,
+            too_large: false,
+        }
+    }
+}
+
+/// The status line and headers of an HTTP/1.1 response, ending with the
+/// blank line.  Content-Length is added from `content_length`.
+pub fn response_head(status: &str, headers: &[(&str, String)], content_length: usize) -> String {
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+
+    for &(name, ref value) in headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n\r\n", content_length));
+
+    head
+}
+
+/// The path of a request target, without the query string or fragment.
+/// Only this part may be logged or echoed back, as the query string may
+/// hold an access token.
+pub fn target_path(url: &str) -> &str {
+    url.split(|c| c == '?' || c == '#').next().unwrap_or("")
+}
+
+/// Decode the percent escapes in part of a URL, and "+" as a space if
+/// `plus_as_space` (as in query strings).  None if an escape is broken or
+/// the result is not UTF-8.
+pub fn percent_decode(s: &str, plus_as_space: bool) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if i + 2 >= bytes.len() {
+                return None;
+            }
+            // from_str_radix alone would take a sign, as in "%+1"
+            if !bytes[i + 1].is_ascii_hexdigit() || !bytes[i + 2].is_ascii_hexdigit() {
+                return None;
+            }
+            let hex = ::std::str::from_utf8(&bytes[i + 1..i + 3]).expect("Hex digits are ASCII");
+            decoded.push(u8::from_str_radix(hex, 16).expect("Checked as hex digits"));
+            i += 3;
+        } else if bytes[i] == b'+' && plus_as_space {
+            decoded.push(b' ');
+            i += 1;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Collects a request's URL and headers as the parser finds them.  The
+/// parser may hand over a name or value in several pieces, so each header is
+/// only stored once the next one starts (or the headers end).
 pub struct HttpParser {
-    pub current_key: Option<String>,
-    pub headers: Arc<Mutex<HashMap<String, String>>>
+    pub request: Arc<Mutex<HttpRequest>>,
+    field: Vec<u8>,
+    value: Vec<u8>,
+    in_value: bool,
+    size: usize,
+    /// Set at the end of the first request's headers.  Anything after that
+    /// is ignored:  a new parser is used for the next request on a
+    /// keep-alive connection, so pipelined requests are not supported.
+    done: bool,
+}
+
+impl HttpParser {
+    pub fn new(request: Arc<Mutex<HttpRequest>>) -> HttpParser {
+        HttpParser {
+            request: request,
+            field: Vec::new(),
+            value: Vec::new(),
+            in_value: false,
+            size: 0,
+            done: false,
+        }
+    }
+
+    /// Store the header collected so far, if any.  Returns false if the
+    /// request has gone over the limits.
+    fn finish_header(&mut self) -> bool {
+        if !self.in_value {
+            return true;
+        }
+
+        let mut request = self.request.lock().unwrap();
+
+        if request.headers.len() >= MAX_HEADERS {
+            request.too_large = true;
+            return false;
+        }
+
+        // Bad bytes are replaced, rather than trusted or panicked over
+        request.headers.push(String::from_utf8_lossy(&self.field).into_owned(),
+                             String::from_utf8_lossy(&self.value).into_owned());
+
+        self.field.clear();
+        self.value.clear();
+        self.in_value = false;
+        true
+    }
+
+    fn count(&mut self, len: usize) -> bool {
+        self.size += len;
+        if self.size > MAX_HEADERS_SIZE {
+            self.request.lock().unwrap().too_large = true;
+            return false;
+        }
+        true
+    }
 }
 
 impl ParserHandler for HttpParser {
+    fn on_url(&mut self, s: &[u8]) -> bool {
+        if self.done {
+            return true;
+        }
+        if !self.count(s.len()) {
+            return false;
+        }
+        self.request.lock().unwrap().url.push_str(&String::from_utf8_lossy(s));
+        true
+    }
+
     fn on_header_field(&mut self, s: &[u8]) -> bool {
-        self.current_key = Some(std::str::from_utf8(s).unwrap().to_string());
+        if self.done {
+            return true;
+        }
+        if !self.finish_header() || !self.count(s.len()) {
+            return false;
+        }
+        self.field.extend_from_slice(s);
         true
     }
 
     fn on_header_value(&mut self, s: &[u8]) -> bool {
-        self.headers.lock().unwrap()
-            .insert(self.current_key.clone().unwrap(),
-                    std::str::from_utf8(s).unwrap().to_string());
+        if self.done {
+            return true;
+        }
+        if !self.count(s.len()) {
+            return false;
+        }
+        self.value.extend_from_slice(s);
+        self.in_value = true;
         true
     }
 
     fn on_headers_complete(&mut self) -> bool {
+        if self.done {
+            return false;
+        }
+        if !self.finish_header() {
+            return false;
+        }
+        self.request.lock().unwrap().complete = true;
+        self.done = true;
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Headers,percent_decode};
+
+    fn headers(entries: &[(&str, &str)]) -> Headers {
+        let mut headers = Headers::new();
+        for &(name, value) in entries.iter() {
+            headers.push(name.to_owned(), value.to_owned());
+        }
+        headers
+    }
+
+    #[test]
+    fn looks_up_names_ignoring_case() {
+        let headers = headers(&[("Upgrade", " websocket "), ("X-Other", "1")]);
+        assert!(headers.contains("upgrade"));
+        assert!(!headers.contains("Origin"));
+        assert_eq!(headers.get_one("UPGRADE"), Some("websocket"));
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    fn get_one_refuses_repeated_headers() {
+        let headers = headers(&[("Sec-WebSocket-Key", "a"), ("sec-websocket-key", "b")]);
+        assert_eq!(headers.get_one("Sec-WebSocket-Key"), None);
+        assert_eq!(headers.get_all("Sec-WebSocket-Key"), vec!["a", "b"]);
+        assert_eq!(headers.get_one("Origin"), None);
+    }
+
+    #[test]
+    fn joins_list_headers() {
+        let headers = headers(&[("Sec-WebSocket-Protocol", "chat.v1"),
+                                ("Sec-WebSocket-Protocol", "echo, ,other")]);
+        assert_eq!(headers.get_joined("Sec-WebSocket-Protocol"),
+                   Some("chat.v1, echo, ,other".to_owned()));
+        assert_eq!(headers.tokens("Sec-WebSocket-Protocol"), vec!["chat.v1", "echo", "other"]);
+        assert_eq!(headers.get_joined("Origin"), None);
+    }
+
+    #[test]
+    fn finds_tokens_ignoring_case() {
+        let headers = headers(&[("Connection", "keep-alive, Upgrade")]);
+        assert!(headers.has_token("connection", "upgrade"));
+        assert!(headers.has_token("Connection", "Keep-Alive"));
+        assert!(!headers.has_token("Connection", "close"));
+    }
+
+    #[test]
+    fn percent_decodes() {
+        assert_eq!(percent_decode("a%20b%2Fc", false), Some("a b/c".to_owned()));
+        assert_eq!(percent_decode("a+b", false), Some("a+b".to_owned()));
+        assert_eq!(percent_decode("a+b", true), Some("a b".to_owned()));
+        assert_eq!(percent_decode("%C3%A9", false), Some("\u{e9}".to_owned()));
+    }
+
+    #[test]
+    fn refuses_broken_escapes() {
+        assert_eq!(percent_decode("%", false), None);
+        assert_eq!(percent_decode("%4", false), None);
+        assert_eq!(percent_decode("%zz", false), None);
+        assert_eq!(percent_decode("%+1", false), None);
+        assert_eq!(percent_decode("%-1", false), None);
+        assert_eq!(percent_decode("%ff", false), None);
+    }
+}
@@ -0,0 +1,32 @@
+extern crate mio;
+extern crate threadpool;
+extern crate num_cpus;
+extern crate rustc_serialize;
+extern crate http_muncher;
+extern crate sha1;
+extern crate byteorder;
+extern crate time;
+extern crate flate2;
+extern crate rand;
+extern crate crypto;
+
+pub mod handler;
+pub mod server;
+pub mod client;
+pub mod event_message;
+pub mod http_parser;
+pub mod websocket_frame;
+pub mod websocket_client;
+pub mod room;
+pub mod nick;
+pub mod protocol;
+pub mod command;
+pub mod config;
+pub mod history;
+pub mod extension;
+pub mod deflate;
+pub mod static_files;
+pub mod origin;
+pub mod route;
+pub mod auth;
+pub mod account;
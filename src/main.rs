@@ -1,32 +1,21 @@
-
 extern crate mio;
-extern crate threadpool;
-extern crate num_cpus;
-extern crate rustc_serialize;
-extern crate http_muncher;
-extern crate sha1;
-extern crate byteorder;
-extern crate time;
-
-mod handler;
-mod server;
-mod client;
-mod event_message;
-mod http_parser;
-mod websocket_frame;
-
-use std::net::SocketAddr;
+extern crate chat;
+
 use mio::EventLoop;
-use handler::EventHandler;
-use server::Server;
+use chat::handler::EventHandler;
+use chat::server::Server;
+use chat::config::Config;
 
 
 fn main() {
 
     // Create the server
-    let server = {
-        let address = "127.0.0.1:10000".parse::<SocketAddr>().unwrap();
-        Server::new(&address)
+    let server = match Server::new(Config::default()) {
+        Ok(server) => server,
+        Err(e) => {
+            println!("Failed to start the server: {}", e);
+            ::std::process::exit(1);
+        },
     };
 
     // Create the event handler
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::fmt;
+use mio::Token;
+
+pub const MAX_NICK_LEN: usize = 24;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum NickError {
+    Invalid(String),
+    Taken(String),
+}
+
+impl fmt::Display for NickError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NickError::Invalid(ref nick) => write!(f, "Invalid nickname: {}", nick),
+            NickError::Taken(ref nick) => write!(f, "Nickname {} is already taken", nick),
+        }
+    }
+}
+
+/// Tracks which client owns each nickname.  Nicknames are unique across
+/// the server, compared case-insensitively.
+pub struct Nicks {
+    owners: HashMap<String, Token>,
+}
+
+impl Nicks {
+    pub fn new() -> Nicks {
+        Nicks {
+            owners: HashMap::new(),
+        }
+    }
+
+    /// Nicknames start with a letter and contain only letters, digits and
+    /// a few punctuation characters
+    pub fn is_valid_name(nick: &str) -> bool {
+        nick.len() <= MAX_NICK_LEN
+            && nick.chars().next().map_or(false, |c| c.is_ascii_alphabetic())
+            && nick.chars().all(|c| c.is_ascii_alphanumeric() || "-_[]".contains(c))
+    }
+
+    /// Claim a nickname for the client.  Claiming a nickname the client
+    /// already owns (perhaps in a different case) succeeds.
+    pub fn claim(&mut self, nick: &str, token: Token) -> Result<(), NickError> {
+        if !Nicks::is_valid_name(nick) {
+            return Err(NickError::Invalid(nick.to_owned()));
+        }
+
+        let key = nick.to_lowercase();
+
+        match self.owners.get(&key) {
+            Some(owner) if *owner != token => return Err(NickError::Taken(nick.to_owned())),
+            _ => {},
+        }
+
+        self.owners.insert(key, token);
+        Ok(())
+    }
+
+    /// Release a nickname, if the client owns it
+    pub fn release(&mut self, nick: &str, token: Token) {
+        let key = nick.to_lowercase();
+
+        if self.owners.get(&key) == Some(&token) {
+            self.owners.remove(&key);
+        }
+    }
+
+    pub fn owner(&self, nick: &str) -> Option<Token> {
+        self.owners.get(&nick.to_lowercase()).cloned()
+    }
+}
@@ -0,0 +1,117 @@
+//! Checking a handshake's Origin header against the configured allowlist.
+//! Browsers send the origin of the page opening the socket, so this stops
+//! other sites' pages from connecting on their visitors' behalf.
+
+/// Whether an origin (such as "https://chat.example.com") is allowed.  With
+/// an empty list, only the same origin is:  the origin's host (and port)
+/// must be the `Host` the request was sent to.
+///
+/// Each entry is either "*", which allows anything, an exact origin, or an
+/// origin whose host starts with "*.", such as "https://*.example.com",
+/// which allows any subdomain (but not example.com itself).  The scheme
+/// and port must match.  Comparisons ignore case.
+pub fn is_allowed(origin: &str, host: Option<&str>, allowed: &[String]) -> bool {
+    if allowed.is_empty() {
+        return is_same_origin(origin, host);
+    }
+    allowed.iter().any(|pattern| matches(origin, pattern))
+}
+
+fn is_same_origin(origin: &str, host: Option<&str>) -> bool {
+    match (split_origin(origin), host) {
+        (Some((_, origin_host)), Some(host)) => origin_host.eq_ignore_ascii_case(host.trim()),
+        _ => false,
+    }
+}
+
+fn matches(origin: &str, pattern: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+
+    let origin = origin.to_lowercase();
+    let pattern = pattern.trim_end_matches('/').to_lowercase();
+
+    let (scheme, host) = match split_origin(&pattern) {
+        Some(parts) => parts,
+        None => return origin == pattern,
+    };
+
+    if !host.starts_with("*.") {
+        return origin == pattern;
+    }
+
+    let (origin_scheme, origin_host) = match split_origin(&origin) {
+        Some(parts) => parts,
+        None => return false,
+    };
+
+    // host[1..] keeps the dot, so "*.example.com" needs at least one more
+    // label in front of "example.com"
+    let suffix = &host[1..];
+    origin_scheme == scheme
+        && origin_host.len() > suffix.len()
+        && origin_host.ends_with(suffix)
+        && !origin_host[..origin_host.len() - suffix.len()].contains(|c| c == ':' || c == '/')
+}
+
+/// The scheme and host (with any port) of an origin
+fn split_origin(origin: &str) -> Option<(&str, &str)> {
+    let separator = match origin.find("://") {
+        Some(separator) => separator,
+        None => return None,
+    };
+    Some((&origin[..separator], &origin[separator + 3..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_allowed;
+
+    fn allowed(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn matches_an_exact_origin() {
+        let list = allowed(&["https://chat.example.com"]);
+        assert!(is_allowed("https://chat.example.com", None, &list));
+        assert!(!is_allowed("https://other.example.com", None, &list));
+        assert!(!is_allowed("http://chat.example.com", None, &list));
+    }
+
+    #[test]
+    fn wildcards_match_subdomains_only() {
+        let list = allowed(&["https://*.example.com"]);
+        assert!(is_allowed("https://a.example.com", None, &list));
+        assert!(is_allowed("https://a.b.example.com", None, &list));
+
+        assert!(!is_allowed("https://example.com", None, &list));
+        assert!(!is_allowed("https://evilexample.com", None, &list));
+        assert!(!is_allowed("http://a.example.com", None, &list));
+        assert!(!is_allowed("https://a.example.com:8443", None, &list));
+    }
+
+    #[test]
+    fn ignores_case() {
+        assert!(is_allowed("HTTPS://Chat.Example.COM", None, &allowed(&["https://chat.example.com"])));
+        assert!(is_allowed("https://A.EXAMPLE.com", None, &allowed(&["https://*.Example.com"])));
+    }
+
+    #[test]
+    fn a_star_allows_anything() {
+        assert!(is_allowed("https://anywhere.test", None, &allowed(&["*"])));
+        assert!(is_allowed("null", None, &allowed(&["*"])));
+    }
+
+    #[test]
+    fn only_the_same_origin_by_default() {
+        assert!(is_allowed("http://localhost:10000", Some("localhost:10000"), &[]));
+        assert!(is_allowed("https://Chat.example.com", Some("chat.example.com"), &[]));
+
+        assert!(!is_allowed("http://localhost:10001", Some("localhost:10000"), &[]));
+        assert!(!is_allowed("https://evil.test", Some("chat.example.com"), &[]));
+        assert!(!is_allowed("null", Some("chat.example.com"), &[]));
+        assert!(!is_allowed("https://chat.example.com", None, &[]));
+    }
+}
@@ -0,0 +1,224 @@
+//! The JSON message protocol spoken inside text frames.
+//!
+//! Clients may ask for it by the subprotocol name `chat.v1`; clients that
+//! ask for no subprotocol get it too.
+//!
+//! Every text frame carries one JSON object, the envelope:
+//!
+//! ```text
+//! {
+//!   "version": 1,             required, must be PROTOCOL_VERSION
+//!   "type": "message",        required, see below
+//!   "room": "lobby",          the room the message is for, if any
+//!   "from": "alice",          the sender's nickname (server to client only)
+//!   "to": "bob",              the recipient's nickname (private messages)
+//!   "body": "hello",          text content
+//!   "id": 42,                 message id assigned by the server
+//!   "timestamp": 1447050000,  seconds since the unix epoch (server to client)
+//!   "code": "not_in_room",    machine readable error code (errors only)
+//!   "data": ...               structured content for listings
+//! }
+//! ```
+//!
+//! Client to server types:  `message` (room, body), `private` (to, body),
+//! `join` (room; with an id, replays the history after that message
+//! rather than the latest messages), `part` (room), `nick` (body) and
+//! `rooms` and `online`.
+//!
+//! A `message` body starting with a slash is run as a command instead, for
+//! example "/join lobby" (see the `command` module).
+//!
+//! Server to client types:  `message` and `emote` (room, from, body),
+//! `private` (from, to, body), `delivered` (to, body; confirms a private
+//! message was delivered, with its id), `notice` (body), `error` (code, body), `rooms`
+//! (data is a list of `{"name", "members"}` objects), `who` (room, data is
+//! a list of nicknames), `online` (data is a list of nicknames) and
+//! `presence` (room, from, body is one of `join`, `leave` or `quit`; or
+//! body `nick` with the new nickname in `to`).
+
+use std::collections::BTreeMap;
+use std::fmt;
+use rustc_serialize::json::Json;
+
+pub const PROTOCOL_VERSION: u64 = 1;
+
+/// The Sec-WebSocket-Protocol name of this protocol
+pub const SUBPROTOCOL: &'static str = "chat.v1";
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum ProtocolError {
+    Malformed(String),
+    UnsupportedVersion(u64),
+    MissingField(&'static str),
+    UnknownType(String),
+}
+
+impl ProtocolError {
+    /// The machine readable code sent in `error` replies
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ProtocolError::Malformed(_) => "malformed",
+            ProtocolError::UnsupportedVersion(_) => "unsupported_version",
+            ProtocolError::MissingField(_) => "missing_field",
+            ProtocolError::UnknownType(_) => "unknown_type",
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolError::Malformed(ref s) => write!(f, "Malformed message: {}", s),
+            ProtocolError::UnsupportedVersion(v) => write!(f, "Unsupported protocol version: {}", v),
+            ProtocolError::MissingField(field) => write!(f, "Missing field: {}", field),
+            ProtocolError::UnknownType(ref t) => write!(f, "Unknown message type: {}", t),
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Envelope {
+    pub kind: String,
+    pub room: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub body: Option<String>,
+    pub id: Option<u64>,
+    pub timestamp: Option<i64>,
+    pub code: Option<String>,
+    pub data: Option<Json>,
+}
+
+impl Envelope {
+    pub fn new(kind: &str) -> Envelope {
+        Envelope {
+            kind: kind.to_owned(),
+            room: None,
+            from: None,
+            to: None,
+            body: None,
+            id: None,
+            timestamp: None,
+            code: None,
+            data: None,
+        }
+    }
+
+    pub fn notice(body: &str) -> Envelope {
+        let mut envelope = Envelope::new("notice");
+        envelope.body = Some(body.to_owned());
+        envelope
+    }
+
+    pub fn error(code: &str, body: &str) -> Envelope {
+        let mut envelope = Envelope::new("error");
+        envelope.code = Some(code.to_owned());
+        envelope.body = Some(body.to_owned());
+        envelope
+    }
+
+    /// Decode an envelope from the text of a frame
+    pub fn decode(text: &str) -> Result<Envelope, ProtocolError> {
+        let json = try!(Json::from_str(text)
+                        .map_err(|e| ProtocolError::Malformed(format!("{}", e))));
+
+        let object = match json {
+            Json::Object(object) => object,
+            _ => return Err(ProtocolError::Malformed("not a JSON object".to_owned())),
+        };
+
+        match object.get("version") {
+            None => return Err(ProtocolError::MissingField("version")),
+            Some(v) => match v.as_u64() {
+                Some(PROTOCOL_VERSION) => {},
+                Some(other) => return Err(ProtocolError::UnsupportedVersion(other)),
+                None => return Err(ProtocolError::Malformed("version is not a number".to_owned())),
+            },
+        }
+
+        let kind = match object.get("type") {
+            None => return Err(ProtocolError::MissingField("type")),
+            Some(t) => match t.as_string() {
+                Some(t) => t.to_owned(),
+                None => return Err(ProtocolError::Malformed("type is not a string".to_owned())),
+            },
+        };
+
+        Ok(Envelope {
+            kind: kind,
+            room: try!(Self::get_string(&object, "room")),
+            from: try!(Self::get_string(&object, "from")),
+            to: try!(Self::get_string(&object, "to")),
+            body: try!(Self::get_string(&object, "body")),
+            id: match object.get("id") {
+                None | Some(&Json::Null) => None,
+                Some(id) => match id.as_u64() {
+                    Some(id) => Some(id),
+                    None => return Err(ProtocolError::Malformed("id is not a number".to_owned())),
+                },
+            },
+            timestamp: match object.get("timestamp") {
+                None | Some(&Json::Null) => None,
+                Some(ts) => match ts.as_i64() {
+                    Some(ts) => Some(ts),
+                    None => return Err(ProtocolError::Malformed("timestamp is not a number".to_owned())),
+                },
+            },
+            code: try!(Self::get_string(&object, "code")),
+            data: object.get("data").cloned(),
+        })
+    }
+
+    /// Encode the envelope as the text of a frame
+    pub fn encode(&self) -> String {
+        let mut object = BTreeMap::new();
+
+        object.insert("version".to_owned(), Json::U64(PROTOCOL_VERSION));
+        object.insert("type".to_owned(), Json::String(self.kind.clone()));
+
+        if let Some(ref room) = self.room {
+            object.insert("room".to_owned(), Json::String(room.clone()));
+        }
+        if let Some(ref from) = self.from {
+            object.insert("from".to_owned(), Json::String(from.clone()));
+        }
+        if let Some(ref to) = self.to {
+            object.insert("to".to_owned(), Json::String(to.clone()));
+        }
+        if let Some(ref body) = self.body {
+            object.insert("body".to_owned(), Json::String(body.clone()));
+        }
+        if let Some(id) = self.id {
+            object.insert("id".to_owned(), Json::U64(id));
+        }
+        if let Some(timestamp) = self.timestamp {
+            object.insert("timestamp".to_owned(), Json::I64(timestamp));
+        }
+        if let Some(ref code) = self.code {
+            object.insert("code".to_owned(), Json::String(code.clone()));
+        }
+        if let Some(ref data) = self.data {
+            object.insert("data".to_owned(), data.clone());
+        }
+
+        Json::Object(object).to_string()
+    }
+
+    /// The body, or a `missing_field` error
+    pub fn require_body(&self) -> Result<&str, ProtocolError> {
+        match self.body {
+            Some(ref body) => Ok(body),
+            None => Err(ProtocolError::MissingField("body")),
+        }
+    }
+
+    fn get_string(object: &BTreeMap<String, Json>, field: &'static str)
+                  -> Result<Option<String>, ProtocolError>
+    {
+        match object.get(field) {
+            None | Some(&Json::Null) => Ok(None),
+            Some(&Json::String(ref s)) => Ok(Some(s.clone())),
+            Some(_) => Err(ProtocolError::Malformed(format!("{} is not a string", field))),
+        }
+    }
+}
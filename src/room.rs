@@ -0,0 +1,96 @@
+use std::collections::{HashMap, HashSet};
+use mio::Token;
+
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+
+/// Tracks which clients are members of which named rooms.  Rooms are
+/// created on first join and dropped when their last member leaves.
+pub struct Rooms {
+    rooms: HashMap<String, HashSet<Token>>,
+}
+
+impl Rooms {
+    pub fn new() -> Rooms {
+        Rooms {
+            rooms: HashMap::new(),
+        }
+    }
+
+    /// Room names are short and limited to characters that are safe to show
+    /// and to use in file names
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.len() <= MAX_ROOM_NAME_LEN
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '#')
+    }
+
+    /// Add the client to the room, creating it if needed.  Returns false if
+    /// the client was already a member.
+    pub fn join(&mut self, name: &str, token: Token) -> bool {
+        self.rooms.entry(name.to_owned())
+            .or_insert_with(HashSet::new)
+            .insert(token)
+    }
+
+    /// Remove the client from the room, dropping the room if it is now empty.
+    /// Returns false if the client was not a member.
+    pub fn leave(&mut self, name: &str, token: Token) -> bool {
+        let (removed, now_empty) = match self.rooms.get_mut(name) {
+            None => return false,
+            Some(members) => (members.remove(&token), members.is_empty()),
+        };
+
+        if now_empty {
+            self.rooms.remove(name);
+        }
+
+        removed
+    }
+
+    /// Remove the client from every room it is in, returning those rooms
+    pub fn leave_all(&mut self, token: Token) -> Vec<String> {
+        let names = self.rooms_of(token);
+        for name in names.iter() {
+            self.leave(name, token);
+        }
+        names
+    }
+
+    /// Whether the room has any members
+    pub fn exists(&self, name: &str) -> bool {
+        self.rooms.contains_key(name)
+    }
+
+    pub fn is_member(&self, name: &str, token: Token) -> bool {
+        match self.rooms.get(name) {
+            None => false,
+            Some(members) => members.contains(&token),
+        }
+    }
+
+    pub fn members(&self, name: &str) -> Vec<Token> {
+        match self.rooms.get(name) {
+            None => Vec::new(),
+            Some(members) => members.iter().cloned().collect(),
+        }
+    }
+
+    /// The rooms the client is a member of, sorted by name
+    pub fn rooms_of(&self, token: Token) -> Vec<String> {
+        let mut names: Vec<String> = self.rooms.iter()
+            .filter(|&(_, members)| members.contains(&token))
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// All rooms with their member counts, sorted by name
+    pub fn list(&self) -> Vec<(String, usize)> {
+        let mut list: Vec<(String, usize)> = self.rooms.iter()
+            .map(|(name, members)| (name.clone(), members.len()))
+            .collect();
+        list.sort();
+        list
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use mio::{EventLoop,Token};
+use handler::EventHandler;
+use server::Server;
+use http_parser;
+use protocol;
+
+/// A route handler is given the server, the client the text message came
+/// from, and the message
+pub type RouteHandler = fn(&mut Server, &mut EventLoop<EventHandler>, Token, String);
+
+pub struct Route {
+    /// The request path, such as "/chat", without any query string
+    pub path: &'static str,
+    pub description: &'static str,
+    /// The subprotocols (Sec-WebSocket-Protocol) this service speaks.  A
+    /// client that offers subprotocols gets the first of its offers that is
+    /// listed here, and is refused if there is none.
+    pub subprotocols: Vec<String>,
+    pub handler: RouteHandler,
+}
+
+/// The websocket services, looked up by the path of the handshake request.
+/// A handshake for any other path is refused with 404 Not Found.
+pub struct RouteRegistry {
+    routes: BTreeMap<&'static str, Route>,
+}
+
+impl RouteRegistry {
+    pub fn new() -> RouteRegistry {
+        RouteRegistry {
+            routes: BTreeMap::new(),
+        }
+    }
+
+    /// A registry holding the built-in services
+    pub fn with_builtins() -> RouteRegistry {
+        let mut registry = RouteRegistry::new();
+
+        registry.register(Route {
+            path: "/",
+            description: "Chat, speaking the JSON protocol",
+            subprotocols: vec![protocol::SUBPROTOCOL.to_owned()],
+            handler: route_chat,
+        });
+        registry.register(Route {
+            path: "/chat",
+            description: "Same as /",
+            subprotocols: vec![protocol::SUBPROTOCOL.to_owned()],
+            handler: route_chat,
+        });
+        registry.register(Route {
+            path: "/echo",
+            description: "Sends every text message straight back",
+            subprotocols: Vec::new(),
+            handler: route_echo,
+        });
+
+        registry
+    }
+
+    /// Register a route, replacing any existing route for that path
+    pub fn register(&mut self, route: Route) {
+        self.routes.insert(route.path, route);
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Route> {
+        self.routes.get(path)
+    }
+}
+
+/// The route a client connected to, and the parameters from the query
+/// string of its handshake request
+#[derive(Debug,Clone)]
+pub struct RouteMatch {
+    pub path: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl RouteMatch {
+    /// Match a request target, such as "/chat?room=lobby", against the
+    /// routes.  None if there is no route for its path.
+    pub fn find(routes: &RouteRegistry, url: &str) -> Option<RouteMatch> {
+        let url = url.split('#').next().unwrap_or("");
+        let path = http_parser::target_path(url);
+        let query = if path.len() < url.len() { &url[path.len() + 1..] } else { "" };
+
+        let path = match http_parser::percent_decode(path, false) {
+            Some(path) => path,
+            None => return None,
+        };
+
+        routes.get(&path).map(|route| RouteMatch {
+            path: route.path.to_owned(),
+            params: parse_query(query),
+        })
+    }
+
+    /// The first value of a query parameter
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|&&(ref n, _)| n == name)
+            .map(|&(_, ref v)| &**v)
+    }
+}
+
+/// The name and value pairs of a query string such as "room=lobby&quiet",
+/// decoded, in order.  A parameter without "=" has an empty value, and
+/// pairs that do not decode are skipped.
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            let (name, value) = match pair.find('=') {
+                Some(equals) => (&pair[..equals], &pair[equals + 1..]),
+                None => (pair, ""),
+            };
+            match (http_parser::percent_decode(name, true), http_parser::percent_decode(value, true)) {
+                (Some(name), Some(value)) => Some((name, value)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn route_chat(server: &mut Server, event_loop: &mut EventLoop<EventHandler>,
+              client_token: Token, payload: String)
+{
+    server.handle_chat_text(event_loop, client_token, payload);
+}
+
+fn route_echo(server: &mut Server, event_loop: &mut EventLoop<EventHandler>,
+              client_token: Token, payload: String)
+{
+    server.send_text(event_loop, client_token, payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RouteMatch,RouteRegistry,parse_query};
+
+    #[test]
+    fn matches_the_path_only() {
+        let routes = RouteRegistry::with_builtins();
+
+        let found = RouteMatch::find(&routes, "/echo?access_token=abc&room=a%20b#top").unwrap();
+        assert_eq!(found.path, "/echo");
+        assert_eq!(found.param("access_token"), Some("abc"));
+        assert_eq!(found.param("room"), Some("a b"));
+
+        assert_eq!(RouteMatch::find(&routes, "/ch%61t").unwrap().path, "/chat");
+        assert!(RouteMatch::find(&routes, "/nowhere?path=/chat").is_none());
+    }
+
+    #[test]
+    fn parses_queries() {
+        assert_eq!(parse_query("a=1&quiet&&b=x+y&bad=%zz"),
+                   vec![("a".to_owned(), "1".to_owned()),
+                        ("quiet".to_owned(), "".to_owned()),
+                        ("b".to_owned(), "x y".to_owned())]);
+    }
+}
@@ -1,13 +1,25 @@
 
-use std::net::SocketAddr;
-use std::collections::HashMap;
+use std::collections::{BTreeMap,HashMap,HashSet};
+use std::io;
 use std::sync::{Arc,Mutex};
 use threadpool::ThreadPool;
 use mio::tcp::TcpListener;
 use mio::{EventLoop,EventSet,PollOpt,Token};
-use handler::EventHandler;
+use handler::{EventHandler,TimerEvent};
+use event_message::EventMessage;
 use client::Client;
-use websocket_frame::WebSocketFrame;
+use room::Rooms;
+use nick::{Nicks,NickError};
+use protocol::{self,Envelope,ProtocolError};
+use command::{self,CommandRegistry};
+use rustc_serialize::json::Json;
+use time;
+use config::Config;
+use history::History;
+use websocket_frame::{WebSocketFrame,CloseCode};
+use route::RouteMatch;
+use account::{Accounts,AccountError,PasswordJob,PasswordOutcome};
+use auth::Identity;
 
 pub const LISTENER_FD: Token = Token(0);
 
@@ -16,23 +28,56 @@ pub struct Server {
     clients: HashMap<Token, Arc<Mutex<Client>>>,
     next_free_token: usize,
     pool: ThreadPool,
+    config: Arc<Config>,
+    history: History,
+    /// None if accounts are disabled
+    accounts: Option<Accounts>,
+    password_pool: ThreadPool,
+    /// Clients with a password being hashed or checked; each may only have
+    /// one at a time
+    password_jobs: HashSet<Token>,
+    rooms: Rooms,
+    nicks: Nicks,
+    next_message_id: u64,
+    commands: CommandRegistry,
 }
 
 impl Server {
-    pub fn new(address: &SocketAddr) -> Server
+    pub fn new(config: Config) -> io::Result<Server>
     {
         // Create the thread pool
         let pool = ThreadPool::new( ::num_cpus::get() );
 
         // See net2::TcpBuilder if finer-grained control is required
         // (e.g. ipv6 or setting the listen backlog)
-        let listener = TcpListener::bind(address).unwrap();
-        Server {
+        let listener = try!(TcpListener::bind(&config.address));
+
+        let history = try!(History::open(&config.history_dir, config.history_replay));
+        let next_message_id = history.last_id() + 1;
+
+        let accounts = match config.accounts_file {
+            None => None,
+            Some(ref path) => Some(try!(Accounts::open(path, config.max_login_failures,
+                                                       config.login_lockout_secs as i64))),
+        };
+
+        let password_pool = ThreadPool::new(config.password_threads);
+
+        Ok(Server {
             listener: listener,
             clients: HashMap::new(),
             next_free_token: 1,
             pool: pool,
-        }
+            config: Arc::new(config),
+            history: history,
+            accounts: accounts,
+            password_pool: password_pool,
+            password_jobs: HashSet::new(),
+            rooms: Rooms::new(),
+            nicks: Nicks::new(),
+            next_message_id: next_message_id,
+            commands: CommandRegistry::with_builtins(),
+        })
     }
 
     pub fn register(&mut self, event_loop: &mut EventLoop<EventHandler>) {
@@ -62,7 +107,8 @@ impl Server {
         let sender = event_loop.channel();
 
         // Build a new client
-        let client = Arc::new(Mutex::new(Client::new(client_socket, new_token, sender)));
+        let client = Arc::new(Mutex::new(Client::new(client_socket, new_token, sender,
+                                                     self.config.clone())));
 
         // And remember that this token maps to this client
         self.clients.insert(new_token, client.clone());
@@ -70,6 +116,10 @@ impl Server {
         // Register the client's readable events.  This must be after inserting
         // into the map, to be sure the server is actually ready
         client.lock().unwrap().register(event_loop);
+
+        self.schedule_request_timeout(event_loop, new_token, 0);
+
+        println!("Client {:?} connected", new_token);
     }
 
     pub fn handle_client_read(&mut self, _event_loop: &mut EventLoop<EventHandler>,
@@ -110,11 +160,185 @@ impl Server {
         client.lock().unwrap().register(event_loop);
     }
 
-    pub fn handle_client_close(&mut self, client_token: Token) {
-        let _ = self.clients.remove(&client_token);
+    pub fn schedule_heartbeat(&mut self, event_loop: &mut EventLoop<EventHandler>) {
+        event_loop.timeout_ms(TimerEvent::Heartbeat, self.config.heartbeat_interval_ms).unwrap();
+    }
+
+    /// Ping every running client, and close the connections of those that
+    /// have missed too many pongs.  Clients that were cut off (see
+    /// `Client::notify`) are forgotten.
+    pub fn handle_heartbeat(&mut self, event_loop: &mut EventLoop<EventHandler>) {
+        let mut unresponsive: Vec<Token> = Vec::new();
+        let mut cut_off: Vec<Token> = Vec::new();
+
+        for (token, client) in self.clients.iter() {
+            let mut client = client.lock().unwrap();
+
+            if client.is_closed() {
+                cut_off.push(*token);
+                continue;
+            }
+            if !client.is_running() {
+                continue;
+            }
+
+            if client.heartbeat(self.config.max_missed_pongs) {
+                client.register(event_loop);
+            } else {
+                unresponsive.push(*token);
+            }
+        }
+
+        for token in unresponsive.into_iter() {
+            self.close_client(event_loop, token, CloseCode::GoingAway, "ping timeout");
+        }
+        for token in cut_off.into_iter() {
+            self.handle_client_close(event_loop, token);
+        }
+    }
+
+    pub fn schedule_history_sync(&mut self, event_loop: &mut EventLoop<EventHandler>) {
+        event_loop.timeout_ms(TimerEvent::HistorySync,
+                              self.config.history_sync_interval_ms).unwrap();
+    }
+
+    /// Sync the room logs written to since the last time, on the thread
+    /// pool, so a slow disk does not hold up the event loop
+    pub fn handle_history_sync(&mut self) {
+        let files = self.history.take_unsynced();
+        if files.is_empty() {
+            return;
+        }
+
+        self.pool.execute(move || {
+            for file in files.iter() {
+                if let Err(e) = file.sync_data() {
+                    println!("Failed to sync history: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Start the close handshake with a client.  If the client does not
+    /// finish it in time, the connection is dropped anyway.
+    pub fn close_client(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                        client_token: Token, status_code: CloseCode, reason: &str)
+    {
+        let client = match self.clients.get(&client_token) {
+            None => return,
+            Some(client) => client.clone(),
+        };
+
+        let mut client = client.lock().unwrap();
+
+        client.close(status_code, reason);
+        client.register(event_loop);
+
+        self.schedule_close_timeout(event_loop, client_token);
+    }
+
+    fn schedule_close_timeout(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                              client_token: Token)
+    {
+        event_loop.timeout_ms(TimerEvent::CloseTimeout(client_token),
+                              self.config.close_timeout_ms).unwrap();
+    }
+
+    /// Re-arm a kept alive HTTP connection for its next request, which must
+    /// arrive in good time
+    pub fn handle_client_awaiting_request(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                                          client_token: Token)
+    {
+        let responses_sent = match self.clients.get(&client_token) {
+            None => return,
+            Some(client) => client.lock().unwrap().http_responses_sent(),
+        };
+
+        if let Some(responses_sent) = responses_sent {
+            self.schedule_request_timeout(event_loop, client_token, responses_sent);
+        }
+
+        self.handle_client_rearm(event_loop, client_token);
     }
 
-    pub fn handle_client_close_request(&mut self, client_token: Token, close_frame: WebSocketFrame) {
+    fn schedule_request_timeout(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                                client_token: Token, responses_sent: u64)
+    {
+        event_loop.timeout_ms(TimerEvent::RequestTimeout(client_token, responses_sent),
+                              self.config.request_timeout_ms).unwrap();
+    }
+
+    /// Drop a connection that is still waiting on the same HTTP request as
+    /// when the timeout was scheduled, so that slow or idle connections
+    /// cannot use up every file descriptor
+    pub fn handle_request_timeout(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                                  client_token: Token, responses_sent: u64)
+    {
+        let client = match self.clients.get(&client_token) {
+            None => return,
+            Some(client) => client.clone(),
+        };
+
+        {
+            let mut client = client.lock().unwrap();
+            if client.http_responses_sent() != Some(responses_sent) {
+                // Upgraded, closed, or on to a later request
+                return;
+            }
+
+            println!("Client {:?} did not send a request in time", client_token);
+            client.shutdown();
+        }
+
+        self.handle_client_close(event_loop, client_token);
+    }
+
+    /// The client took too long over the close handshake, so drop it
+    pub fn handle_close_timeout(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                                client_token: Token)
+    {
+        let client = match self.clients.get(&client_token) {
+            None => return,
+            Some(client) => client.clone(),
+        };
+
+        println!("Client {:?} did not finish closing in time", client_token);
+
+        client.lock().unwrap().shutdown();
+
+        self.handle_client_close(event_loop, client_token);
+    }
+
+    /// Forget a client that has closed or gone away, telling the members
+    /// of its rooms that it has quit
+    pub fn handle_client_close(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                               client_token: Token)
+    {
+        let client = match self.clients.remove(&client_token) {
+            None => return,
+            Some(client) => client,
+        };
+
+        println!("Client {:?} disconnected", client_token);
+
+        let rooms = self.rooms.leave_all(client_token);
+        for room in rooms.iter() {
+            self.close_history_if_empty(room);
+        }
+
+        let nick = client.lock().unwrap().nick.clone();
+        if let Some(nick) = nick {
+            self.nicks.release(&nick, client_token);
+
+            for room in rooms.iter() {
+                self.send_presence(event_loop, room, &nick, "quit", None);
+            }
+        }
+    }
+
+    pub fn handle_client_close_request(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                                       client_token: Token, close_frame: WebSocketFrame)
+    {
         let client = match self.clients.get_mut(&client_token) {
             None => return,
             Some(client) => client.clone(),
@@ -122,7 +346,16 @@ impl Server {
 
         let mut client = client.lock().unwrap();
 
+        let was_closing = client.is_closing();
+
         client.handle_close_request(close_frame);
+        client.register(event_loop);
+
+        if !was_closing && client.is_closing() {
+            // We are echoing the peer's close frame; don't wait forever for
+            // it to be taken
+            self.schedule_close_timeout(event_loop, client_token);
+        }
     }
 
     pub fn handle_client_ping(&mut self, client_token: Token, payload: WebSocketFrame) {
@@ -147,15 +380,787 @@ impl Server {
         client.handle_pong(payload);
     }
 
-    pub fn handle_client_text_frame(&mut self, client_token: Token, payload: String) {
-        let client = match self.clients.get_mut(&client_token) {
+    pub fn handle_client_text_frame(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                                    client_token: Token, payload: String)
+    {
+        let path = {
+            let client = match self.clients.get_mut(&client_token) {
+                None => return,
+                Some(client) => client.clone(),
+            };
+
+            let client = client.lock().unwrap();
+            if !client.is_running() {
+                // Do nothing if not yet setup
+                return;
+            }
+            match client.route {
+                Some(ref route) => route.path.clone(),
+                None => return,
+            }
+        };
+
+        // Hand the message to the service the client connected to
+        let handler = match self.config.routes.get(&path) {
+            Some(route) => route.handler,
+            None => return,
+        };
+        handler(self, event_loop, client_token, payload);
+    }
+
+    /// Handle a text message from a client of the chat service
+    pub fn handle_chat_text(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                            client_token: Token, payload: String)
+    {
+        let protocol = match self.clients.get(&client_token) {
+            None => return,
+            Some(client) => client.lock().unwrap().protocol.clone(),
+        };
+
+        match protocol.as_ref().map(|p| &**p) {
+            None | Some(protocol::SUBPROTOCOL) => { },
+            Some(other) => {
+                // Registered for a route, but not one the chat handler speaks
+                println!("No handler for subprotocol {}", other);
+                self.close_client(event_loop, client_token, CloseCode::Unsupported,
+                                  "unsupported subprotocol");
+                return;
+            },
+        }
+
+        let envelope = match Envelope::decode(&payload) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                // It may still hold a password or a private message
+                println!("Undecodable text received: {}", e.code());
+                self.send_error(event_loop, client_token, e.code(), &format!("{}", e));
+                return;
+            },
+        };
+
+        // Commands may carry passwords, and bodies may be private, so only
+        // the envelope type and command name are logged
+        match envelope.body {
+            Some(ref body) if envelope.kind == "message" && body.starts_with('/') => {
+                println!("Command received: {}", command::split_command(body).map_or("", |(name, _)| name));
+            },
+            _ => println!("Envelope received: {}", envelope.kind),
+        }
+
+        match &*envelope.kind {
+            "message" => self.handle_message(event_loop, client_token, envelope),
+            "private" => match (envelope.to.as_ref(), envelope.require_body()) {
+                (Some(to), Ok(body)) => self.send_private_message(event_loop, client_token, to, body),
+                (None, _) => self.send_protocol_error(event_loop, client_token,
+                                                      ProtocolError::MissingField("to")),
+                (_, Err(e)) => self.send_protocol_error(event_loop, client_token, e),
+            },
+            "join" => match envelope.room {
+                Some(ref room) => self.join_room(event_loop, client_token, room, envelope.id),
+                None => self.send_protocol_error(event_loop, client_token,
+                                                 ProtocolError::MissingField("room")),
+            },
+            "part" => match envelope.room {
+                Some(ref room) => self.leave_room(event_loop, client_token, room),
+                None => self.leave_current_room(event_loop, client_token),
+            },
+            "nick" => match envelope.require_body() {
+                Ok(nick) => self.set_nick(event_loop, client_token, nick),
+                Err(e) => self.send_protocol_error(event_loop, client_token, e),
+            },
+            "rooms" => self.list_rooms(event_loop, client_token),
+            "online" => self.list_online(event_loop, client_token),
+            other => self.send_protocol_error(event_loop, client_token,
+                                              ProtocolError::UnknownType(other.to_owned())),
+        }
+    }
+
+    fn handle_message(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                      client_token: Token, envelope: Envelope)
+    {
+        let body = match envelope.require_body() {
+            Ok(body) => body.to_owned(),
+            Err(e) => {
+                self.send_protocol_error(event_loop, client_token, e);
+                return;
+            },
+        };
+
+        if body.starts_with('/') {
+            self.handle_command(event_loop, client_token, &body);
+            return;
+        }
+
+        self.send_room_message(event_loop, client_token, "message",
+                               envelope.room.as_ref().map(|r| &**r), &body);
+    }
+
+    fn handle_command(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                      client_token: Token, line: &str)
+    {
+        let (name, rest) = match command::split_command(line) {
+            None => return,
+            Some(split) => split,
+        };
+
+        let parsed = match self.commands.get(name) {
+            None => None,
+            Some(command) => Some(command.parse_args(rest).map(|args| (command.handler, args))),
+        };
+
+        match parsed {
+            None => self.send_error(event_loop, client_token, "unknown_command",
+                                    &format!("Unknown command: /{}.  Try /help.", name)),
+            Some(Err(usage)) => self.send_error(event_loop, client_token, "bad_arguments", &usage),
+            Some(Ok((handler, args))) => handler(self, event_loop, client_token, &args),
+        }
+    }
+
+    pub fn commands(&self) -> &CommandRegistry {
+        &self.commands
+    }
+
+    /// Send a message of the given kind from a client to a room it is in
+    /// (by default, its current room)
+    pub fn send_room_message(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                             client_token: Token, kind: &str, room: Option<&str>,
+                             body: &str)
+    {
+        let (nick, current_room) = match self.clients.get(&client_token) {
+            None => return,
+            Some(client) => {
+                let client = client.lock().unwrap();
+                (client.nick.clone(), client.room.clone())
+            },
+        };
+
+        let nick = match nick {
+            None => {
+                self.send_error(event_loop, client_token, "no_nick",
+                                "Choose a nickname with /nick <name> first.");
+                return;
+            },
+            Some(nick) => nick,
+        };
+
+        let room = match room.map(|r| r.to_owned()).or(current_room) {
+            None => {
+                self.send_error(event_loop, client_token, "not_in_room",
+                                "You are not in a room.  Use /join <room> first.");
+                return;
+            },
+            Some(room) => room,
+        };
+
+        if !self.rooms.is_member(&room, client_token) {
+            self.send_error(event_loop, client_token, "not_in_room",
+                            &format!("You are not in room {}", room));
+            return;
+        }
+
+        let mut message = Envelope::new(kind);
+        message.room = Some(room.clone());
+        message.from = Some(nick);
+        message.body = Some(body.to_owned());
+        message.id = Some(self.next_message_id);
+        message.timestamp = Some(time::get_time().sec);
+        self.next_message_id += 1;
+
+        let encoded = message.encode();
+
+        if let Err(e) = self.history.append(&room, &encoded) {
+            println!("Failed to record history for room {}: {}", room, e);
+        }
+
+        let exclude = if self.config.echo_to_sender { None } else { Some(client_token) };
+
+        let members = self.rooms.members(&room);
+        self.broadcast_frame(event_loop, &members, &WebSocketFrame::from(&*encoded), exclude);
+    }
+
+    /// Send a private message from a client to the user holding a nickname.
+    /// It goes only to that user's connection, and the sender gets either
+    /// a `delivered` confirmation or a `no_such_user` error.
+    pub fn send_private_message(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                                client_token: Token, to_nick: &str, body: &str)
+    {
+        let nick = match self.clients.get(&client_token) {
+            None => return,
+            Some(client) => client.lock().unwrap().nick.clone(),
+        };
+
+        let nick = match nick {
+            None => {
+                self.send_error(event_loop, client_token, "no_nick",
+                                "Choose a nickname with /nick <name> first.");
+                return;
+            },
+            Some(nick) => nick,
+        };
+
+        let recipient = match self.connection_of(to_nick) {
+            None => {
+                self.send_error(event_loop, client_token, "no_such_user",
+                                &format!("No such user: {}", to_nick));
+                return;
+            },
+            Some(recipient) => recipient,
+        };
+
+        let mut message = Envelope::new("private");
+        message.from = Some(nick);
+        message.to = Some(to_nick.to_owned());
+        message.body = Some(body.to_owned());
+        message.id = Some(self.next_message_id);
+        message.timestamp = Some(time::get_time().sec);
+        self.next_message_id += 1;
+
+        self.broadcast_frame(event_loop, &[recipient], &WebSocketFrame::from(&*message.encode()), None);
+
+        message.kind = "delivered".to_owned();
+        message.from = None;
+        self.send_envelope(event_loop, client_token, &message);
+    }
+
+    /// The service a client connected to, and its query parameters
+    pub fn route_of(&self, client_token: Token) -> Option<RouteMatch> {
+        match self.clients.get(&client_token) {
+            None => None,
+            Some(client) => client.lock().unwrap().route.clone(),
+        }
+    }
+
+    /// The running connection of the user with this nickname.  A nickname
+    /// is held by one connection at a time.
+    pub fn connection_of(&self, nick: &str) -> Option<Token> {
+        match self.nicks.owner(nick) {
+            None => None,
+            Some(token) => match self.clients.get(&token) {
+                Some(client) if client.lock().unwrap().is_running() => Some(token),
+                _ => None,
+            },
+        }
+    }
+
+    /// Claim a nickname for a client, releasing any nickname it had before
+    pub fn set_nick(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                    client_token: Token, nick: &str)
+    {
+        let client = match self.clients.get(&client_token) {
+            None => return,
+            Some(client) => client.clone(),
+        };
+
+        // A registered nickname is only for its owner
+        if self.accounts.as_ref().map_or(false, |accounts| accounts.exists(nick)) {
+            let owner = client.lock().unwrap().identity.as_ref()
+                .map_or(false, |identity| identity.subject.to_lowercase() == nick.to_lowercase());
+            if !owner {
+                self.send_error(event_loop, client_token, "nick_reserved",
+                                &format!("{} is registered; use /login {} <password>", nick, nick));
+                return;
+            }
+        }
+
+        if let Err(e) = self.nicks.claim(nick, client_token) {
+            let code = match e {
+                NickError::Invalid(_) => "invalid_nick",
+                NickError::Taken(_) => "nick_taken",
+            };
+            self.send_error(event_loop, client_token, code, &format!("{}", e));
+            return;
+        }
+
+        let old_nick = {
+            let mut client = client.lock().unwrap();
+            ::std::mem::replace(&mut client.nick, Some(nick.to_owned()))
+        };
+
+        if let Some(old_nick) = old_nick {
+            if old_nick.to_lowercase() != nick.to_lowercase() {
+                self.nicks.release(&old_nick, client_token);
+            }
+
+            // Let the client's rooms know about the new name
+            let mut presence = Envelope::new("presence");
+            presence.from = Some(old_nick);
+            presence.to = Some(nick.to_owned());
+            presence.body = Some("nick".to_owned());
+            presence.timestamp = Some(time::get_time().sec);
+
+            for room in self.rooms.rooms_of(client_token).into_iter() {
+                presence.room = Some(room.clone());
+                let members = self.rooms.members(&room);
+                self.broadcast_frame(event_loop, &members,
+                                     &WebSocketFrame::from(&*presence.encode()),
+                                     Some(client_token));
+            }
+        }
+
+        self.send_notice(event_loop, client_token, &format!("You are now known as {}", nick));
+    }
+
+    /// Register the client's current nickname as an account, and log it in
+    pub fn register_account(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                            client_token: Token, password: &str)
+    {
+        let client = match self.clients.get(&client_token) {
+            None => return,
+            Some(client) => client.clone(),
+        };
+
+        let nick = client.lock().unwrap().nick.clone();
+        let nick = match nick {
+            None => {
+                self.send_error(event_loop, client_token, "no_nick",
+                                "Choose a nickname with /nick before registering it");
+                return;
+            },
+            Some(nick) => nick,
+        };
+
+        if self.password_job_running(event_loop, client_token) {
+            return;
+        }
+
+        let job = match self.accounts {
+            None => return self.send_accounts_disabled(event_loop, client_token),
+            Some(ref accounts) => accounts.begin_register(&nick, password),
+        };
+
+        match job {
+            Ok(job) => self.run_password_job(event_loop, client_token, job),
+            Err(e) => self.send_error(event_loop, client_token, e.code(), &format!("{}", e)),
+        }
+    }
+
+    /// Log a client in to an account, and give it the account's nickname
+    pub fn login(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                 client_token: Token, name: &str, password: &str)
+    {
+        if !self.clients.contains_key(&client_token) {
+            return;
+        }
+
+        if self.password_job_running(event_loop, client_token) {
+            return;
+        }
+
+        let job = match self.accounts {
+            None => return self.send_accounts_disabled(event_loop, client_token),
+            Some(ref mut accounts) => accounts.begin_login(name, password, time::get_time().sec),
+        };
+
+        match job {
+            Ok(job) => self.run_password_job(event_loop, client_token, job),
+            Err(e) => self.login_failed(event_loop, client_token, name, e),
+        }
+    }
+
+    /// Change the password of the account the client is logged in to
+    pub fn change_password(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                           client_token: Token, old_password: &str, new_password: &str)
+    {
+        let identity = match self.clients.get(&client_token) {
+            None => return,
+            Some(client) => client.lock().unwrap().identity.clone(),
+        };
+
+        if self.password_job_running(event_loop, client_token) {
+            return;
+        }
+
+        let job = match self.accounts {
+            None => return self.send_accounts_disabled(event_loop, client_token),
+            Some(ref mut accounts) => match identity {
+                Some(ref identity) if accounts.exists(&identity.subject) => {
+                    Some(accounts.begin_change_password(&identity.subject, old_password,
+                                                        new_password, time::get_time().sec))
+                },
+                _ => None,
+            },
+        };
+
+        match job {
+            None => self.send_error(event_loop, client_token, "not_logged_in",
+                                    "Log in to an account with /login first"),
+            Some(Ok(job)) => self.run_password_job(event_loop, client_token, job),
+            Some(Err(e)) => self.send_error(event_loop, client_token, e.code(), &format!("{}", e)),
+        }
+    }
+
+    /// Refuse a password command while the client's last one is still being
+    /// worked on, so one client cannot queue up a pile of slow hashes
+    fn password_job_running(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                            client_token: Token) -> bool
+    {
+        if !self.password_jobs.contains(&client_token) {
+            return false;
+        }
+
+        self.send_error(event_loop, client_token, AccountError::Busy.code(),
+                        "Your last password is still being checked; try again shortly");
+        true
+    }
+
+    /// Hash or check a password on the password pool.  The outcome comes
+    /// back to `handle_password_outcome`.
+    fn run_password_job(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                        client_token: Token, job: PasswordJob)
+    {
+        self.password_jobs.insert(client_token);
+
+        let sender = event_loop.channel();
+        self.password_pool.execute(move || {
+            if sender.send(EventMessage::Password(client_token, job.run())).is_err() {
+                println!("Password outcome for client {:?} lost: the event queue is full",
+                         client_token);
+            }
+        });
+    }
+
+    /// Finish a /register, /login or /passwd once its password has been
+    /// hashed or checked
+    pub fn handle_password_outcome(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                                   client_token: Token, outcome: PasswordOutcome)
+    {
+        self.password_jobs.remove(&client_token);
+
+        let now = time::get_time().sec;
+        let accounts = match self.accounts {
+            None => return,
+            Some(ref mut accounts) => accounts,
+        };
+
+        match outcome {
+            PasswordOutcome::Register { name, hash } => {
+                match accounts.finish_register(&name, hash) {
+                    Ok(()) => {
+                        println!("Client {:?} registered account {}", client_token, name);
+                        let identity = Identity { subject: name.clone(), expires: None };
+                        if let Some(client) = self.clients.get(&client_token) {
+                            client.lock().unwrap().identity = Some(identity);
+                        }
+                        self.send_notice(event_loop, client_token,
+                                         &format!("Registered {}; you are logged in", name));
+                    },
+                    Err(e) => {
+                        if let AccountError::Io(ref e) = e {
+                            println!("Saving account {} failed: {}", name, e);
+                        }
+                        self.send_error(event_loop, client_token, e.code(), &format!("{}", e));
+                    },
+                }
+            },
+            PasswordOutcome::Login { name, matched } => {
+                match accounts.finish_login(&name, matched, now) {
+                    Ok(registered) => {
+                        println!("Client {:?} logged in as {}", client_token, registered);
+                        self.logged_in(event_loop, client_token, registered);
+                    },
+                    Err(e) => self.login_failed(event_loop, client_token, &name, e),
+                }
+            },
+            PasswordOutcome::ChangePassword { name, hash } => {
+                match accounts.finish_change_password(&name, hash, now) {
+                    Ok(()) => {
+                        println!("Client {:?} changed the password of {}", client_token, name);
+                        self.send_notice(event_loop, client_token, "Password changed");
+                    },
+                    Err(e) => self.send_error(event_loop, client_token, e.code(), &format!("{}", e)),
+                }
+            },
+        }
+    }
+
+    /// Give a client the identity and nickname of the account it logged in to
+    fn logged_in(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                 client_token: Token, name: String)
+    {
+        let client = match self.clients.get(&client_token) {
+            None => return,
+            Some(client) => client.clone(),
+        };
+
+        let nick = {
+            let mut client = client.lock().unwrap();
+            client.identity = Some(Identity { subject: name.clone(), expires: None });
+            client.nick.clone()
+        };
+
+        self.send_notice(event_loop, client_token, &format!("Logged in as {}", name));
+
+        if nick.as_ref().map(|n| &**n) != Some(&*name) {
+            self.set_nick(event_loop, client_token, &name);
+        }
+    }
+
+    fn login_failed(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                    client_token: Token, name: &str, e: AccountError)
+    {
+        println!("Failed login to {} from client {:?}: {}", name, client_token, e);
+        self.send_error(event_loop, client_token, e.code(), &format!("{}", e));
+    }
+
+    fn send_accounts_disabled(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                              client_token: Token)
+    {
+        self.send_error(event_loop, client_token, "accounts_disabled",
+                        "Accounts are not enabled on this server");
+    }
+
+    /// Add a client to a room (creating it if needed) and make it the room
+    /// that client's messages go to.  The room's recent history is replayed
+    /// to the client:  the messages after `since` if given, otherwise the
+    /// last few.
+    pub fn join_room(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                     client_token: Token, room: &str, since: Option<u64>)
+    {
+        if !Rooms::is_valid_name(room) {
+            self.send_error(event_loop, client_token, "invalid_room",
+                            &format!("Invalid room name: {}", room));
+            return;
+        }
+
+        let client = match self.clients.get(&client_token) {
+            None => return,
+            Some(client) => client.clone(),
+        };
+
+        let nick = {
+            let mut client = client.lock().unwrap();
+            client.room = Some(room.to_owned());
+            client.nick.clone()
+        };
+
+        // Clients without a nickname join unannounced, as they leave
+        let newly_joined = self.rooms.join(room, client_token);
+        if let (true, Some(nick)) = (newly_joined, nick) {
+            self.send_presence(event_loop, room, &nick, "join", Some(client_token));
+        }
+
+        self.send_notice(event_loop, client_token, &format!("Joined room {}", room));
+
+        let replay = match self.history.replay(room, since, self.config.history_replay) {
+            Ok(replay) => replay,
+            Err(e) => {
+                println!("Failed to read history for room {}: {}", room, e);
+                return;
+            },
+        };
+
+        let mut client = client.lock().unwrap();
+        for encoded in replay.into_iter() {
+            client.send_text_frame(encoded);
+        }
+        client.register(event_loop);
+    }
+
+    /// Remove a client from a room.  If it was the client's current room,
+    /// one of its remaining rooms (if any) becomes current.
+    pub fn leave_room(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                      client_token: Token, room: &str)
+    {
+        let client = match self.clients.get(&client_token) {
+            None => return,
+            Some(client) => client.clone(),
+        };
+
+        if !self.rooms.leave(room, client_token) {
+            self.send_error(event_loop, client_token, "not_in_room",
+                            &format!("You are not in room {}", room));
+            return;
+        }
+        self.close_history_if_empty(room);
+
+        let nick = {
+            let mut client = client.lock().unwrap();
+            if client.room.as_ref().map(|r| &**r) == Some(room) {
+                client.room = self.rooms.rooms_of(client_token).into_iter().next();
+            }
+            client.nick.clone()
+        };
+
+        if let Some(nick) = nick {
+            self.send_presence(event_loop, room, &nick, "leave", None);
+        }
+
+        self.send_notice(event_loop, client_token, &format!("Left room {}", room));
+    }
+
+    /// Release a room's history once its last member has left
+    fn close_history_if_empty(&mut self, room: &str) {
+        if !self.rooms.exists(room) {
+            self.history.close_room(room);
+        }
+    }
+
+    pub fn leave_current_room(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                          client_token: Token)
+    {
+        let room = self.clients.get(&client_token)
+            .and_then(|client| client.lock().unwrap().room.clone());
+
+        match room {
+            Some(room) => self.leave_room(event_loop, client_token, &room),
+            None => self.send_error(event_loop, client_token, "not_in_room",
+                                    "You are not in a room."),
+        }
+    }
+
+    pub fn list_rooms(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                      client_token: Token)
+    {
+        let list = self.rooms.list();
+
+        let data = list.iter()
+            .map(|&(ref name, count)| {
+                let mut room = BTreeMap::new();
+                room.insert("name".to_owned(), Json::String(name.clone()));
+                room.insert("members".to_owned(), Json::U64(count as u64));
+                Json::Object(room)
+            })
+            .collect();
+
+        let mut reply = Envelope::new("rooms");
+        reply.data = Some(Json::Array(data));
+
+        self.send_envelope(event_loop, client_token, &reply);
+    }
+
+    /// List the nicknames of the members of a room (by default, the
+    /// client's current room)
+    pub fn list_members(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                        client_token: Token, room: Option<&str>)
+    {
+        let room = match room.map(|r| r.to_owned()).or_else(|| {
+            self.clients.get(&client_token).and_then(|client| client.lock().unwrap().room.clone())
+        }) {
+            None => {
+                self.send_error(event_loop, client_token, "not_in_room",
+                                "You are not in a room.  Name one: /who <room>");
+                return;
+            },
+            Some(room) => room,
+        };
+
+        let mut nicks: Vec<String> = self.rooms.members(&room).iter()
+            .filter_map(|token| self.clients.get(token))
+            .filter_map(|client| client.lock().unwrap().nick.clone())
+            .collect();
+        nicks.sort();
+
+        let mut reply = Envelope::new("who");
+        reply.room = Some(room);
+        reply.data = Some(Json::Array(nicks.into_iter().map(Json::String).collect()));
+
+        self.send_envelope(event_loop, client_token, &reply);
+    }
+
+    /// List the nicknames of everyone online (connected and named)
+    pub fn list_online(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                       client_token: Token)
+    {
+        let mut nicks: Vec<String> = self.clients.values()
+            .filter_map(|client| {
+                let client = client.lock().unwrap();
+                if client.is_running() { client.nick.clone() } else { None }
+            })
+            .collect();
+        nicks.sort();
+
+        let mut reply = Envelope::new("online");
+        reply.data = Some(Json::Array(nicks.into_iter().map(Json::String).collect()));
+
+        self.send_envelope(event_loop, client_token, &reply);
+    }
+
+    /// Tell the members of a room that someone joined, left or quit
+    fn send_presence(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                     room: &str, nick: &str, event: &str, exclude: Option<Token>)
+    {
+        let mut presence = Envelope::new("presence");
+        presence.room = Some(room.to_owned());
+        presence.from = Some(nick.to_owned());
+        presence.body = Some(event.to_owned());
+        presence.timestamp = Some(time::get_time().sec);
+
+        let members = self.rooms.members(room);
+        self.broadcast_frame(event_loop, &members, &WebSocketFrame::from(&*presence.encode()), exclude);
+    }
+
+    /// Send an envelope to a single client
+    pub fn send_envelope(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                         client_token: Token, envelope: &Envelope)
+    {
+        self.send_text(event_loop, client_token, envelope.encode());
+    }
+
+    /// Send a text message to a single client
+    pub fn send_text(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                     client_token: Token, text: String)
+    {
+        let client = match self.clients.get(&client_token) {
             None => return,
             Some(client) => client.clone(),
         };
 
         let mut client = client.lock().unwrap();
 
-        client.handle_text_frame(payload);
+        client.send_text_frame(text);
+
+        // Re-register so the client picks up writable events
+        client.register(event_loop);
+    }
+
+    pub fn send_notice(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                       client_token: Token, text: &str)
+    {
+        self.send_envelope(event_loop, client_token, &Envelope::notice(text));
+    }
+
+    pub fn send_error(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                      client_token: Token, code: &str, text: &str)
+    {
+        self.send_envelope(event_loop, client_token, &Envelope::error(code, text));
+    }
+
+    fn send_protocol_error(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                           client_token: Token, error: ProtocolError)
+    {
+        self.send_error(event_loop, client_token, error.code(), &format!("{}", error));
+    }
+
+    /// Send a frame to each of the given running clients, except the
+    /// `exclude`d one if given.  The frame is encoded only once.
+    pub fn broadcast_frame(&mut self, event_loop: &mut EventLoop<EventHandler>,
+                           tokens: &[Token], frame: &WebSocketFrame,
+                           exclude: Option<Token>)
+    {
+        let mut encoded: Vec<u8> = Vec::new();
+        frame.write(&mut encoded).unwrap();
+
+        for token in tokens.iter() {
+            if Some(*token) == exclude {
+                continue;
+            }
+
+            let client = match self.clients.get(token) {
+                None => continue,
+                Some(client) => client,
+            };
+
+            let mut client = client.lock().unwrap();
+
+            if !client.is_running() {
+                continue;
+            }
+
+            client.send_shared_frame(frame, &encoded);
+
+            // Re-register so the client picks up writable events
+            client.register(event_loop);
+        }
     }
 
     pub fn handle_client_binary_frame(&mut self, client_token: Token, payload: Vec<u8>) {
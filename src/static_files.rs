@@ -0,0 +1,193 @@
+//! Plain HTTP requests (anything that is not a websocket handshake) are
+//! answered with static files:  from a directory if one is configured,
+//! otherwise from the web chat client built into the binary.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::Read;
+use std::path::Path;
+use http_parser::{self, Headers};
+
+/// The built-in chat client, served when no static directory is configured
+const EMBEDDED: &'static [(&'static str, &'static [u8])] = &[
+    ("index.html", include_bytes!("../assets/index.html")),
+    ("chat.js", include_bytes!("../assets/chat.js")),
+    ("chat.css", include_bytes!("../assets/chat.css")),
+];
+
+/// Served for a request for a directory
+const INDEX: &'static str = "index.html";
+
+/// Answer a plain HTTP request, returning the whole response.  `dir` is
+/// the directory to serve from, or None for the built-in files.
+pub fn serve(dir: Option<&Path>, method: &str, url: &str, headers: &Headers, keep_alive: bool) -> Vec<u8> {
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+
+    if method != "GET" && method != "HEAD" {
+        let body = b"Method not allowed\n";
+        return respond("405 Method Not Allowed",
+                       vec![("Allow", "GET, HEAD".to_owned()),
+                            ("Connection", connection.to_owned()),
+                            ("Content-Type", "text/plain".to_owned())],
+                       body, true);
+    }
+
+    let head_only = method == "HEAD";
+
+    // Never the whole URL, which may carry an access token
+    let logged_path = http_parser::target_path(url);
+
+    let (name, contents) = match find(dir, url) {
+        Some(found) => found,
+        None => {
+            println!("{} {}: not found", method, logged_path);
+            return respond("404 Not Found",
+                           vec![("Connection", connection.to_owned()),
+                                ("Content-Type", "text/plain".to_owned())],
+                           b"Not found\n", !head_only);
+        },
+    };
+
+    let etag = etag(&contents);
+
+    if headers.tokens("If-None-Match").iter().any(|tag| *tag == "*" || tag.trim_start_matches("W/") == etag) {
+        return respond("304 Not Modified",
+                       vec![("Connection", connection.to_owned()),
+                            ("ETag", etag)],
+                       b"", false);
+    }
+
+    println!("{} {}: {} bytes", method, logged_path, contents.len());
+
+    respond("200 OK",
+            vec![("Connection", connection.to_owned()),
+                 ("Content-Type", content_type(&name).to_owned()),
+                 ("ETag", etag)],
+            &contents, !head_only)
+}
+
+fn respond(status: &str, headers: Vec<(&str, String)>, body: &[u8], include_body: bool) -> Vec<u8> {
+    let mut response = http_parser::response_head(status, &headers, body.len()).into_bytes();
+    if include_body {
+        response.extend_from_slice(body);
+    }
+    response
+}
+
+/// The file name and contents for a URL, if it names a file we serve
+fn find(dir: Option<&Path>, url: &str) -> Option<(String, Vec<u8>)> {
+    let path = match url_path(url) {
+        None => return None,
+        Some(path) => path,
+    };
+
+    match dir {
+        None => {
+            let name = if path.is_empty() { INDEX } else { &*path };
+            EMBEDDED.iter()
+                .find(|&&(embedded, _)| embedded == name)
+                .map(|&(embedded, contents)| (embedded.to_owned(), contents.to_vec()))
+        },
+        Some(dir) => {
+            let mut file_path = dir.join(&path);
+            if file_path.is_dir() {
+                file_path = file_path.join(INDEX);
+            }
+
+            let mut contents: Vec<u8> = Vec::new();
+            match File::open(&file_path).and_then(|mut file| file.read_to_end(&mut contents)) {
+                Ok(_) => Some((file_path.to_string_lossy().into_owned(), contents)),
+                Err(_) => None,
+            }
+        },
+    }
+}
+
+/// The path of a URL relative to the root, without the query string and
+/// with percent escapes decoded.  None if it tries to climb out of the
+/// root.
+fn url_path(url: &str) -> Option<String> {
+    let path = http_parser::target_path(url);
+    if !path.starts_with('/') {
+        return None;
+    }
+
+    let path = match http_parser::percent_decode(&path[1..], false) {
+        Some(path) => path,
+        None => return None,
+    };
+
+    if path.starts_with('/') || path.contains('\0') || path.contains('\\')
+        || path.split('/').any(|segment| segment == "..")
+    {
+        return None;
+    }
+
+    Some(path)
+}
+
+/// A strong ETag, from a hash of the contents
+fn etag(contents: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(contents);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+fn content_type(name: &str) -> &'static str {
+    let extension = name.rsplit('.').next().unwrap_or("").to_lowercase();
+
+    match &*extension {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" => "application/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::url_path;
+
+    #[test]
+    fn strips_the_query_and_decodes() {
+        assert_eq!(url_path("/"), Some("".to_owned()));
+        assert_eq!(url_path("/chat.js?v=2#top"), Some("chat.js".to_owned()));
+        assert_eq!(url_path("/a%20b/c.html"), Some("a b/c.html".to_owned()));
+        // "+" is only a space in query strings
+        assert_eq!(url_path("/a+b"), Some("a+b".to_owned()));
+    }
+
+    #[test]
+    fn refuses_to_leave_the_root() {
+        assert_eq!(url_path("/../secret"), None);
+        assert_eq!(url_path("/a/../../secret"), None);
+        assert_eq!(url_path("/%2e%2e/secret"), None);
+        assert_eq!(url_path("/a/%2E%2E%2Fsecret"), None);
+        assert_eq!(url_path("//etc/passwd"), None);
+        assert_eq!(url_path("/%2fetc/passwd"), None);
+        assert_eq!(url_path("/..%5csecret"), None);
+        assert_eq!(url_path("/a%00.html"), None);
+    }
+
+    #[test]
+    fn refuses_malformed_urls() {
+        assert_eq!(url_path("index.html"), None);
+        assert_eq!(url_path("http://example.com/"), None);
+        assert_eq!(url_path("/%zz"), None);
+        assert_eq!(url_path("/%c3"), None);
+    }
+
+    #[test]
+    fn allows_dots_within_names() {
+        assert_eq!(url_path("/..hidden/a..b"), Some("..hidden/a..b".to_owned()));
+    }
+}
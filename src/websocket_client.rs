@@ -0,0 +1,307 @@
+//! A blocking websocket client, for services and tests that talk to the
+//! chat server.  It shares the server's frame code, masking every frame it
+//! sends as RFC 6455 requires of clients.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use rand;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use http_parser::{self, Headers};
+use websocket_frame::{self, WebSocketFrame, OpCode, CloseCode, FrameError};
+
+/// The largest message the client will accept, unless told otherwise
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+/// A message (or control frame) received from the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Already answered with a pong
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The server's status code (if it gave one) and reason.  Our close
+    /// frame has been sent in reply, if it had not been already.
+    Close(Option<u16>, String),
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    /// The server did not accept the websocket handshake
+    Handshake(String),
+    /// The server broke the protocol.  We have sent a close frame with the
+    /// matching status code.
+    Protocol(FrameError),
+    /// The connection has been closed
+    Closed,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientError::Io(ref e) => write!(f, "I/O error: {}", e),
+            ClientError::Handshake(ref s) => write!(f, "Handshake failed: {}", s),
+            ClientError::Protocol(ref e) => write!(f, "Protocol error: {}", e),
+            ClientError::Closed => write!(f, "Connection closed"),
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> ClientError {
+        // Frame errors come wrapped up in io::Errors by WebSocketFrame::read
+        let frame_error = e.get_ref()
+            .and_then(|inner| inner.downcast_ref::<FrameError>())
+            .cloned();
+
+        match frame_error {
+            Some(frame_error) => ClientError::Protocol(frame_error),
+            None => ClientError::Io(e),
+        }
+    }
+}
+
+pub struct WebSocketClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    protocol: Option<String>,
+    max_message_size: usize,
+    /// The opcode and payload so far of a fragmented message
+    fragments: Option<(OpCode, Vec<u8>)>,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl WebSocketClient {
+    /// Connect to `host` (a "host:port" address), upgrading the connection
+    /// on `path`, and offering the subprotocols in `protocols`
+    pub fn connect(host: &str, path: &str, protocols: &[&str]) -> Result<WebSocketClient, ClientError> {
+        let stream = try!(TcpStream::connect(host));
+        let writer = try!(stream.try_clone());
+
+        let mut client = WebSocketClient {
+            reader: BufReader::new(stream),
+            writer: writer,
+            protocol: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            fragments: None,
+            close_sent: false,
+            close_received: false,
+        };
+
+        try!(client.handshake(host, path, protocols));
+
+        Ok(client)
+    }
+
+    /// The subprotocol the server agreed to, if any
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_ref().map(|p| &**p)
+    }
+
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    fn handshake(&mut self, host: &str, path: &str, protocols: &[&str]) -> Result<(), ClientError> {
+        let nonce: [u8; 16] = rand::random();
+        let key = nonce.to_base64(STANDARD);
+
+        let protocol_header = if protocols.is_empty() {
+            String::new()
+        } else {
+            format!("Sec-WebSocket-Protocol: {}\r\n", protocols.join(", "))
+        };
+
+        let request = format!("GET {} HTTP/1.1\r\n\
+                               Host: {}\r\n\
+                               Upgrade: websocket\r\n\
+                               Connection: Upgrade\r\n\
+                               Sec-WebSocket-Key: {}\r\n\
+                               Sec-WebSocket-Version: 13\r\n\
+                               {}\r\n",
+                              path, host, key, protocol_header);
+        try!(self.writer.write_all(request.as_bytes()));
+
+        let mut status_line = String::new();
+        try!(self.reader.read_line(&mut status_line));
+        if status_line.split_whitespace().nth(1) != Some("101") {
+            return Err(ClientError::Handshake(format!("Unexpected response: {}", status_line.trim())));
+        }
+
+        let mut headers = Headers::new();
+        loop {
+            let mut line = String::new();
+            if try!(self.reader.read_line(&mut line)) == 0 {
+                return Err(ClientError::Handshake("Connection closed during the handshake".to_owned()));
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(colon) = line.find(':') {
+                headers.push(line[..colon].trim().to_owned(), line[colon + 1..].trim().to_owned());
+            }
+        }
+
+        if !headers.has_token("Upgrade", "websocket") {
+            return Err(ClientError::Handshake("Missing Upgrade: websocket".to_owned()));
+        }
+
+        if !headers.has_token("Connection", "Upgrade") {
+            return Err(ClientError::Handshake("Missing Connection: Upgrade".to_owned()));
+        }
+
+        if headers.get_one("Sec-WebSocket-Accept") != Some(&*http_parser::accept_key(&key)) {
+            return Err(ClientError::Handshake("Wrong Sec-WebSocket-Accept".to_owned()));
+        }
+
+        if headers.contains("Sec-WebSocket-Extensions") {
+            // We offered none
+            return Err(ClientError::Handshake("Unrequested extensions".to_owned()));
+        }
+
+        match headers.get_joined("Sec-WebSocket-Protocol") {
+            None => { },
+            Some(protocol) => {
+                if !protocols.contains(&&*protocol) {
+                    return Err(ClientError::Handshake(format!("Unrequested subprotocol: {}", protocol)));
+                }
+                self.protocol = Some(protocol);
+            },
+        }
+
+        Ok(())
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<(), ClientError> {
+        self.send_frame(WebSocketFrame::from(text))
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> Result<(), ClientError> {
+        self.send_frame(WebSocketFrame::from(data.to_vec()))
+    }
+
+    pub fn send_ping(&mut self, payload: &[u8]) -> Result<(), ClientError> {
+        self.send_frame(WebSocketFrame::ping(payload.to_vec()))
+    }
+
+    /// Start the close handshake.  Keep calling `receive` until it returns
+    /// the server's close, then drop the client.
+    pub fn close(&mut self, status_code: CloseCode, reason: &str) -> Result<(), ClientError> {
+        if self.close_sent {
+            return Ok(());
+        }
+
+        let frame = try!(WebSocketFrame::close(status_code, reason.as_bytes())
+                         .map_err(|e| ClientError::Io(io::Error::new(io::ErrorKind::Other, e))));
+        try!(self.send_frame(frame));
+        self.close_sent = true;
+        Ok(())
+    }
+
+    fn send_frame(&mut self, frame: WebSocketFrame) -> Result<(), ClientError> {
+        if self.close_sent {
+            // Nothing may follow a close frame
+            return Err(ClientError::Closed);
+        }
+
+        let mut frame = frame;
+        frame.set_mask(rand::random());
+
+        let mut encoded: Vec<u8> = Vec::new();
+        try!(frame.write(&mut encoded));
+        try!(self.writer.write_all(&encoded));
+        Ok(())
+    }
+
+    /// Wait for the next message or control frame from the server,
+    /// reassembling fragmented messages
+    pub fn receive(&mut self) -> Result<Message, ClientError> {
+        if self.close_received {
+            return Err(ClientError::Closed);
+        }
+
+        loop {
+            let frame = match WebSocketFrame::read(&mut self.reader, 0, self.max_message_size) {
+                Ok(frame) => frame,
+                Err(e) => return Err(self.fail(ClientError::from(e))),
+            };
+
+            let message = match frame.get_opcode() {
+                OpCode::TextFrame | OpCode::BinaryFrame => {
+                    if self.fragments.is_some() {
+                        return Err(self.fail(ClientError::Protocol(FrameError::UnfinishedMessage)));
+                    }
+                    if !frame.is_final() {
+                        self.fragments = Some((frame.get_opcode(), frame.payload));
+                        continue;
+                    }
+                    try!(self.message(frame.get_opcode(), frame.payload))
+                },
+                OpCode::Continuation => {
+                    let (opcode, mut payload) = match self.fragments.take() {
+                        None => return Err(self.fail(ClientError::Protocol(FrameError::UnexpectedContinuation))),
+                        Some(fragments) => fragments,
+                    };
+                    if payload.len() + frame.payload.len() > self.max_message_size {
+                        let too_big = FrameError::TooBig((payload.len() + frame.payload.len()) as u64);
+                        return Err(self.fail(ClientError::Protocol(too_big)));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if !frame.is_final() {
+                        self.fragments = Some((opcode, payload));
+                        continue;
+                    }
+                    try!(self.message(opcode, payload))
+                },
+                OpCode::Ping => {
+                    if !self.close_sent {
+                        try!(self.send_frame(WebSocketFrame::pong(&frame)));
+                    }
+                    Message::Ping(frame.payload)
+                },
+                OpCode::Pong => Message::Pong(frame.payload),
+                OpCode::ConnectionClose => {
+                    self.close_received = true;
+                    if !self.close_sent {
+                        try!(self.send_frame(WebSocketFrame::close_from(&frame)));
+                        self.close_sent = true;
+                    }
+                    let (status_code, reason) = if frame.payload.len() >= 2 {
+                        let status_code = ((frame.payload[0] as u16) << 8) | (frame.payload[1] as u16);
+                        // The reason was checked to be UTF-8 when it was read
+                        (Some(status_code), String::from_utf8_lossy(&frame.payload[2..]).into_owned())
+                    } else {
+                        (None, String::new())
+                    };
+                    Message::Close(status_code, reason)
+                },
+            };
+
+            return Ok(message);
+        }
+    }
+
+    fn message(&mut self, opcode: OpCode, payload: Vec<u8>) -> Result<Message, ClientError> {
+        match opcode {
+            OpCode::TextFrame => match websocket_frame::decode_text(payload) {
+                Ok(text) => Ok(Message::Text(text)),
+                Err(e) => Err(self.fail(ClientError::Protocol(e))),
+            },
+            OpCode::BinaryFrame => Ok(Message::Binary(payload)),
+            _ => unreachable!("Only data frames carry messages"),
+        }
+    }
+
+    /// Close the connection after the server broke the protocol, passing
+    /// back the error
+    fn fail(&mut self, error: ClientError) -> ClientError {
+        if let ClientError::Protocol(ref e) = error {
+            let _ = self.close(e.close_code(), &format!("{}", e));
+        }
+        error
+    }
+}
@@ -2,7 +2,7 @@
 * Copied verbatim from https://github.com/nbaksalyar/mio-websocket/blob/master/src/frame.rs
 * Used under the terms of the MIT license
 */
-use std::{io, iter, u16};
+use std::{cmp, fmt, io, iter, u16};
 use std::io::{Read, Write, ErrorKind, Cursor};
 use std::error::Error;
 
@@ -11,10 +11,128 @@ use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
 const PAYLOAD_LEN_U16: u8 = 126;
 const PAYLOAD_LEN_U64: u8 = 127;
 
+/// The largest payload a control frame may carry
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// The RSV1 bit, as it appears in the first byte of a frame
+pub const RSV1: u8 = 0x40;
+/// The RSV2 bit
+pub const RSV2: u8 = 0x20;
+/// The RSV3 bit
+pub const RSV3: u8 = 0x10;
+
+/// Status codes sent in close frames (RFC 6455, section 7.4.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum CloseCode {
+    Normal = 1000,
+    /// The endpoint is going away
+    GoingAway = 1001,
+    /// The peer broke the protocol
+    ProtocolError = 1002,
+    /// The peer sent a kind of data we cannot accept
+    Unsupported = 1003,
+    /// The message data was invalid, such as text that is not UTF-8
+    InvalidData = 1007,
+    PolicyViolation = 1008,
+    /// The message is too big to process
+    MessageTooBig = 1009,
+    InternalError = 1011,
+}
+
+impl CloseCode {
+    /// Whether a peer may send this status code in a close frame
+    pub fn is_valid(code: u16) -> bool {
+        match code {
+            1000..=1003 | 1007..=1011 => true,
+            3000..=4999 => true,
+            _ => false,
+        }
+    }
+}
+
+/// A frame that breaks RFC 6455
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameError {
+    InvalidOpcode(u8),
+    /// A reserved bit was set that no negotiated extension uses
+    ReservedBits(u8),
+    /// A frame from a client was not masked
+    Unmasked,
+    /// A frame from a server was masked
+    Masked,
+    /// A control frame had its FIN bit clear
+    FragmentedControl,
+    /// A control frame's payload was over 125 bytes
+    ControlTooLong(usize),
+    /// A 64 bit payload length had its most significant bit set
+    InvalidLength,
+    /// A payload was longer than the limit
+    TooBig(u64),
+    /// A close frame's payload was a single byte
+    InvalidClosePayload,
+    InvalidCloseCode(u16),
+    /// Text (or a close reason) was not valid UTF-8
+    InvalidUtf8,
+    /// A continuation frame with no fragmented message to continue
+    UnexpectedContinuation,
+    /// A new message began before the fragmented one was finished
+    UnfinishedMessage,
+}
+
+impl FrameError {
+    /// The status code to close the connection with
+    pub fn close_code(&self) -> CloseCode {
+        match *self {
+            FrameError::InvalidUtf8 => CloseCode::InvalidData,
+            FrameError::TooBig(_) => CloseCode::MessageTooBig,
+            _ => CloseCode::ProtocolError,
+        }
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrameError::InvalidOpcode(op) => write!(f, "Invalid opcode: {}", op),
+            FrameError::ReservedBits(bits) => write!(f, "Reserved bits set: {:#x}", bits),
+            FrameError::Unmasked => write!(f, "Unmasked frame from a client"),
+            FrameError::Masked => write!(f, "Masked frame from a server"),
+            FrameError::FragmentedControl => write!(f, "Fragmented control frame"),
+            FrameError::ControlTooLong(len) => write!(f, "Control frame payload too long: {}", len),
+            FrameError::InvalidLength => write!(f, "Invalid payload length"),
+            FrameError::TooBig(len) => write!(f, "Frame too big: {} bytes", len),
+            FrameError::InvalidClosePayload => write!(f, "Close frame payload of one byte"),
+            FrameError::InvalidCloseCode(code) => write!(f, "Invalid close status code: {}", code),
+            FrameError::InvalidUtf8 => write!(f, "Invalid UTF-8"),
+            FrameError::UnexpectedContinuation => write!(f, "Continuation without a message to continue"),
+            FrameError::UnfinishedMessage => write!(f, "New message before the last was finished"),
+        }
+    }
+}
+
+impl Error for FrameError {
+    fn description(&self) -> &str {
+        "invalid websocket frame"
+    }
+}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> io::Error {
+        io::Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+/// The text of a (possibly reassembled) text message
+pub fn decode_text(payload: Vec<u8>) -> Result<String, FrameError> {
+    String::from_utf8(payload).map_err(|_| FrameError::InvalidUtf8)
+}
+
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[allow(dead_code)]
 pub enum OpCode {
+    Continuation = 0,
     TextFrame = 1,
     BinaryFrame = 2,
     ConnectionClose = 8,
@@ -23,8 +141,14 @@ pub enum OpCode {
 }
 
 impl OpCode {
+    /// Whether this is a control frame (close, ping or pong)
+    pub fn is_control(&self) -> bool {
+        (*self as u8) & 0x8 == 0x8
+    }
+
     fn from(op: u8) -> Option<OpCode> {
         match op {
+            0 => Some(OpCode::Continuation),
             1 => Some(OpCode::TextFrame),
             2 => Some(OpCode::BinaryFrame),
             8 => Some(OpCode::ConnectionClose),
@@ -99,12 +223,29 @@ impl<'a> From<&'a str> for WebSocketFrame {
 }
 
 impl WebSocketFrame {
-    pub fn close(status_code: u16, reason: &[u8]) -> Result<WebSocketFrame, String> {
+    /// A final data frame with the given opcode
+    pub fn data(opcode: OpCode, payload: Vec<u8>) -> WebSocketFrame {
+        WebSocketFrame {
+            header: WebSocketFrameHeader::new_header(payload.len(), opcode),
+            payload: payload,
+            mask: None
+        }
+    }
+
+    /// A close frame.  The reason is cut short, on a UTF-8 character
+    /// boundary, to fit a control frame's payload.
+    pub fn close(status_code: CloseCode, reason: &[u8]) -> Result<WebSocketFrame, String> {
+        let mut reason_len = cmp::min(reason.len(), MAX_CONTROL_PAYLOAD - 2);
+        while reason_len < reason.len() && reason_len > 0 && reason[reason_len] & 0xc0 == 0x80 {
+            reason_len -= 1;
+        }
+        let reason = &reason[..reason_len];
+
         let body = Vec::with_capacity(2 + reason.len());
 
         let mut body_cursor = Cursor::new(body);
 
-        try!(body_cursor.write_u16::<BigEndian>(status_code)
+        try!(body_cursor.write_u16::<BigEndian>(status_code as u16)
              .map_err(|e| e.description().to_string()));
 
         try!(body_cursor.write(reason)
@@ -118,7 +259,7 @@ impl WebSocketFrame {
     }
 
     pub fn close_from(recv_frame: &WebSocketFrame) -> WebSocketFrame {
-        let body = if recv_frame.payload.len() > 0 {
+        let body = if recv_frame.payload.len() >= 2 {
             let status_code = &recv_frame.payload[0..2];
             let mut body = Vec::with_capacity(2);
             body.write(status_code).unwrap();
@@ -144,12 +285,19 @@ impl WebSocketFrame {
 
     pub fn ping(payload: Vec<u8>) -> WebSocketFrame {
         WebSocketFrame {
-            header: WebSocketFrameHeader::new_header(4, OpCode::Ping),
+            header: WebSocketFrameHeader::new_header(payload.len(), OpCode::Ping),
             payload: payload,
             mask: None
         }
     }
 
+    /// Mask the payload with this key when the frame is written, as frames
+    /// sent by a client must be
+    pub fn set_mask(&mut self, mask: [u8; 4]) {
+        self.mask = Some(mask);
+        self.header.masked = true;
+    }
+
     pub fn write<W: Write>(&self, output: &mut W) -> io::Result<()> {
         let hdr = Self::serialize_header(&self.header);
         try!(output.write_u16::<BigEndian>(hdr));
@@ -160,15 +308,30 @@ impl WebSocketFrame {
             _ => {}
         }
 
-        try!(output.write(&self.payload));
+        match self.mask {
+            Some(mask) if self.header.masked => {
+                try!(output.write_all(&mask));
+                let mut payload = self.payload.clone();
+                Self::apply_mask(mask, &mut payload);
+                try!(output.write_all(&payload));
+            },
+            _ => {
+                try!(output.write(&self.payload));
+            },
+        }
         Ok(())
     }
 
-    pub fn read<R: Read>(input: &mut R) -> io::Result<WebSocketFrame> {
+    /// Read one frame from a server, blocking until it is all there.
+    /// `allowed_rsv` and `max_size` are as for `parse`.  A frame that breaks
+    /// the protocol gives an `InvalidData` error wrapping the `FrameError`.
+    pub fn read<R: Read>(input: &mut R, allowed_rsv: u8, max_size: usize) -> io::Result<WebSocketFrame> {
         let buf = try!(input.read_u16::<BigEndian>());
-        let header = try!(Self::parse_header(buf).map_err(|s| io::Error::new(ErrorKind::Other, s)));
+        let header = try!(Self::check_header(buf, allowed_rsv, false));
 
         let len = try!(Self::read_length(header.payload_length, input));
+        try!(Self::check_length(len, max_size));
+        let len = len as usize;
         let mask_key = if header.masked {
             let mask = try!(Self::read_mask(input));
             Some(mask)
@@ -181,6 +344,10 @@ impl WebSocketFrame {
             Self::apply_mask(mask, &mut payload);
         }
 
+        if header.opcode == OpCode::ConnectionClose {
+            try!(Self::validate_close_payload(&payload));
+        }
+
         Ok(WebSocketFrame {
             header: header,
             payload: payload,
@@ -188,10 +355,104 @@ impl WebSocketFrame {
         })
     }
 
+    /// Decode one frame from a client from the front of `input`, which need
+    /// not hold a whole frame.  Returns `None` if more data is needed,
+    /// otherwise the frame and the number of bytes of `input` it took up.
+    ///
+    /// `allowed_rsv` holds the reserved bits (`RSV1` and so on) that the
+    /// negotiated extensions give a meaning to; any other reserved bit is an
+    /// error.  A payload longer than `max_size` bytes is refused as soon as
+    /// its length is known, before waiting for the rest of it.
+    pub fn parse(input: &[u8], allowed_rsv: u8, max_size: usize)
+                 -> Result<Option<(WebSocketFrame, usize)>, FrameError>
+    {
+        if input.len() < 2 {
+            return Ok(None);
+        }
+
+        let header = try!(Self::check_header(((input[0] as u16) << 8) | (input[1] as u16),
+                                             allowed_rsv, true));
+        let mut offset = 2;
+
+        let len_size = match header.payload_length {
+            PAYLOAD_LEN_U16 => 2,
+            PAYLOAD_LEN_U64 => 8,
+            _ => 0,
+        };
+        if input.len() < offset + len_size {
+            return Ok(None);
+        }
+        let len = {
+            let mut cursor = Cursor::new(&input[offset..offset + len_size]);
+            Self::read_length(header.payload_length, &mut cursor)
+                .expect("The length bytes are all there")
+        };
+        try!(Self::check_length(len, max_size));
+        let len = len as usize;
+        offset += len_size;
+
+        let mask_key = if header.masked {
+            if input.len() < offset + 4 {
+                return Ok(None);
+            }
+            let mut mask = [0; 4];
+            mask.copy_from_slice(&input[offset..offset + 4]);
+            offset += 4;
+            Some(mask)
+        } else {
+            None
+        };
+
+        if input.len() - offset < len {
+            return Ok(None);
+        }
+
+        let mut payload = input[offset..offset + len].to_vec();
+        offset += len;
+
+        if let Some(mask) = mask_key {
+            Self::apply_mask(mask, &mut payload);
+        }
+
+        if header.opcode == OpCode::ConnectionClose {
+            try!(Self::validate_close_payload(&payload));
+        }
+
+        Ok(Some((WebSocketFrame {
+            header: header,
+            payload: payload,
+            mask: mask_key
+        }, offset)))
+    }
+
     pub fn get_opcode(&self) -> OpCode {
         self.header.opcode.clone()
     }
 
+    /// Whether this is a text, binary or continuation frame
+    pub fn is_data(&self) -> bool {
+        (self.header.opcode as u8) < 8
+    }
+
+    /// The reserved bits that are set, as `RSV1 | RSV2 | RSV3` would be.
+    /// Their meaning is up to the negotiated extensions.
+    pub fn rsv_bits(&self) -> u8 {
+        (if self.header.rsv1 { RSV1 } else { 0 })
+            | (if self.header.rsv2 { RSV2 } else { 0 })
+            | (if self.header.rsv3 { RSV3 } else { 0 })
+    }
+
+    pub fn set_rsv_bits(&mut self, bits: u8) {
+        self.header.rsv1 = bits & RSV1 != 0;
+        self.header.rsv2 = bits & RSV2 != 0;
+        self.header.rsv3 = bits & RSV3 != 0;
+    }
+
+    /// Whether this is the last frame of a message (the FIN bit)
+    pub fn is_final(&self) -> bool {
+        self.header.fin
+    }
+
     pub fn is_close(&self) -> bool {
         self.header.opcode == OpCode::ConnectionClose
     }
@@ -209,7 +470,66 @@ impl WebSocketFrame {
         ((b1 as u16) << 8) | (b2 as u16)
     }
 
-    fn parse_header(buf: u16) -> Result<WebSocketFrameHeader, String> {
+    /// Parse the first two bytes of a frame, checking them against the
+    /// rules for frames from a client (`from_client`) or from a server
+    fn check_header(buf: u16, allowed_rsv: u8, from_client: bool)
+                    -> Result<WebSocketFrameHeader, FrameError>
+    {
+        let reserved = ((buf >> 8) as u8) & (RSV1 | RSV2 | RSV3) & !allowed_rsv;
+        if reserved != 0 {
+            return Err(FrameError::ReservedBits(reserved));
+        }
+
+        let header = try!(Self::parse_header(buf));
+        if from_client && !header.masked {
+            return Err(FrameError::Unmasked);
+        }
+        if !from_client && header.masked {
+            return Err(FrameError::Masked);
+        }
+        if header.opcode.is_control() {
+            if !header.fin {
+                return Err(FrameError::FragmentedControl);
+            }
+            if header.payload_length as usize > MAX_CONTROL_PAYLOAD {
+                return Err(FrameError::ControlTooLong(header.payload_length as usize));
+            }
+        }
+
+        Ok(header)
+    }
+
+    fn check_length(len: u64, max_size: usize) -> Result<(), FrameError> {
+        if len & (1 << 63) != 0 {
+            // The most significant bit must be 0
+            return Err(FrameError::InvalidLength);
+        }
+        if len > max_size as u64 {
+            return Err(FrameError::TooBig(len));
+        }
+        Ok(())
+    }
+
+    /// A close frame's payload is empty, or a valid status code followed by
+    /// a UTF-8 reason
+    fn validate_close_payload(payload: &[u8]) -> Result<(), FrameError> {
+        match payload.len() {
+            0 => Ok(()),
+            1 => Err(FrameError::InvalidClosePayload),
+            _ => {
+                let status_code = ((payload[0] as u16) << 8) | (payload[1] as u16);
+                if !CloseCode::is_valid(status_code) {
+                    return Err(FrameError::InvalidCloseCode(status_code));
+                }
+                match ::std::str::from_utf8(&payload[2..]) {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(FrameError::InvalidUtf8),
+                }
+            },
+        }
+    }
+
+    fn parse_header(buf: u16) -> Result<WebSocketFrameHeader, FrameError> {
         let opcode_num = ((buf >> 8) as u8) & 0x0F;
         let opcode = OpCode::from(opcode_num);
 
@@ -225,7 +545,7 @@ impl WebSocketFrame {
                 payload_length: (buf as u8) & 0x7F,
             })
         } else {
-            Err(format!("Invalid opcode: {}", opcode_num))
+            Err(FrameError::InvalidOpcode(opcode_num))
         }
     }
 
@@ -237,22 +557,158 @@ impl WebSocketFrame {
 
     fn read_mask<R: Read>(input: &mut R) -> io::Result<[u8; 4]> {
         let mut buf = [0; 4];
-        try!(input.read(&mut buf));
+        try!(input.read_exact(&mut buf));
         Ok(buf)
     }
 
     fn read_payload<R: Read>(payload_len: usize, input: &mut R) -> io::Result<Vec<u8>> {
         let mut payload: Vec<u8> = Vec::with_capacity(payload_len);
         payload.extend(iter::repeat(0).take(payload_len));
-        try!(input.read(&mut payload));
+        try!(input.read_exact(&mut payload));
         Ok(payload)
     }
 
-    fn read_length<R: Read>(payload_len: u8, input: &mut R) -> io::Result<usize> {
+    // The length is kept as a u64 until it has been checked against the
+    // size limit, as it may not fit in a usize
+    fn read_length<R: Read>(payload_len: u8, input: &mut R) -> io::Result<u64> {
         return match payload_len {
-            PAYLOAD_LEN_U64 => input.read_u64::<BigEndian>().map(|v| v as usize).map_err(From::from),
-            PAYLOAD_LEN_U16 => input.read_u16::<BigEndian>().map(|v| v as usize).map_err(From::from),
-            _ => Ok(payload_len as usize) // payload_len < 127
+            PAYLOAD_LEN_U64 => input.read_u64::<BigEndian>().map_err(From::from),
+            PAYLOAD_LEN_U16 => input.read_u16::<BigEndian>().map(|v| v as u64).map_err(From::from),
+            _ => Ok(payload_len as u64) // payload_len < 127
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    /// The bytes of a frame as a client would send it
+    fn client_bytes(mut frame: WebSocketFrame) -> Vec<u8> {
+        frame.set_mask(MASK);
+        let mut bytes = Vec::new();
+        frame.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn parses_a_masked_text_frame() {
+        // The example from RFC 6455, section 5.7
+        let bytes = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let (frame, used) = WebSocketFrame::parse(&bytes, 0, 1024).unwrap().unwrap();
+        assert_eq!(used, bytes.len());
+        assert_eq!(frame.get_opcode(), OpCode::TextFrame);
+        assert!(frame.is_final());
+        assert_eq!(frame.payload, b"Hello");
+    }
+
+    #[test]
+    fn waits_for_the_whole_frame() {
+        let bytes = client_bytes(WebSocketFrame::from("Hello"));
+        for len in 0..bytes.len() {
+            assert!(WebSocketFrame::parse(&bytes[..len], 0, 1024).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn parses_extended_lengths() {
+        for &len in [125, 126, 65535, 65536].iter() {
+            let payload = vec![b'x'; len];
+            let mut bytes = client_bytes(WebSocketFrame::from(payload.clone()));
+            let frame_len = bytes.len();
+            bytes.extend_from_slice(b"next");
+
+            let (frame, used) = WebSocketFrame::parse(&bytes, 0, 1 << 20).unwrap().unwrap();
+            assert_eq!(used, frame_len);
+            assert_eq!(frame.payload, payload);
+        }
+    }
+
+    #[test]
+    fn refuses_a_frame_too_big_before_it_arrives() {
+        let bytes = client_bytes(WebSocketFrame::from(vec![0; 2000]));
+        assert_eq!(WebSocketFrame::parse(&bytes[..8], 0, 1000).unwrap_err(),
+                   FrameError::TooBig(2000));
+    }
+
+    #[test]
+    fn refuses_an_invalid_length() {
+        let mut bytes = vec![0x82, 0xff];
+        bytes.extend_from_slice(&[0x80, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(WebSocketFrame::parse(&bytes, 0, 1000).unwrap_err(), FrameError::InvalidLength);
+    }
+
+    #[test]
+    fn refuses_unmasked_frames() {
+        let mut bytes = Vec::new();
+        WebSocketFrame::from("Hello").write(&mut bytes).unwrap();
+        assert_eq!(WebSocketFrame::parse(&bytes, 0, 1024).unwrap_err(), FrameError::Unmasked);
+    }
+
+    #[test]
+    fn checks_reserved_bits() {
+        let mut frame = WebSocketFrame::from("Hello");
+        frame.set_rsv_bits(RSV1);
+        let bytes = client_bytes(frame);
+
+        assert_eq!(WebSocketFrame::parse(&bytes, 0, 1024).unwrap_err(), FrameError::ReservedBits(RSV1));
+        let (frame, _) = WebSocketFrame::parse(&bytes, RSV1, 1024).unwrap().unwrap();
+        assert_eq!(frame.rsv_bits(), RSV1);
+    }
+
+    #[test]
+    fn refuses_bad_opcodes_and_control_frames() {
+        assert_eq!(WebSocketFrame::parse(&[0x83, 0x80, 0, 0, 0, 0], 0, 1024).unwrap_err(),
+                   FrameError::InvalidOpcode(3));
+        // A ping without FIN
+        assert_eq!(WebSocketFrame::parse(&[0x09, 0x80, 0, 0, 0, 0], 0, 1024).unwrap_err(),
+                   FrameError::FragmentedControl);
+        assert_eq!(WebSocketFrame::parse(&[0x89, 0xfe, 0, 126], 0, 1024).unwrap_err(),
+                   FrameError::ControlTooLong(126));
+    }
+
+    #[test]
+    fn validates_close_payloads() {
+        assert_eq!(WebSocketFrame::validate_close_payload(b""), Ok(()));
+        assert_eq!(WebSocketFrame::validate_close_payload(&[0x03, 0xe8]), Ok(()));
+        assert_eq!(WebSocketFrame::validate_close_payload(&[0x0f, 0xa0, b'o', b'k']), Ok(()));
+        assert_eq!(WebSocketFrame::validate_close_payload(&[0x03]),
+                   Err(FrameError::InvalidClosePayload));
+        // 1005 and 1006 must never be sent
+        assert_eq!(WebSocketFrame::validate_close_payload(&[0x03, 0xed]),
+                   Err(FrameError::InvalidCloseCode(1005)));
+        assert_eq!(WebSocketFrame::validate_close_payload(&[0x03, 0xe8, 0xff]),
+                   Err(FrameError::InvalidUtf8));
+    }
+
+    #[test]
+    fn truncates_long_close_reasons() {
+        let long = [b'x'; 200];
+        let frame = WebSocketFrame::close(CloseCode::Normal, &long).unwrap();
+        assert_eq!(frame.payload.len(), MAX_CONTROL_PAYLOAD);
+
+        // 'é' is two bytes, so the 123rd byte starts a character that cannot fit
+        let mut reason = vec![b'x'; 122];
+        reason.extend_from_slice("éé".as_bytes());
+        let frame = WebSocketFrame::close(CloseCode::Normal, &reason).unwrap();
+        assert_eq!(&frame.payload[2..], &reason[..122]);
+        assert_eq!(WebSocketFrame::validate_close_payload(&frame.payload), Ok(()));
+    }
+
+    #[test]
+    fn parse_checks_close_payloads() {
+        let bytes = client_bytes(WebSocketFrame::close(CloseCode::Normal, b"bye").unwrap());
+        let (frame, _) = WebSocketFrame::parse(&bytes, 0, 1024).unwrap().unwrap();
+        assert!(frame.is_close());
+        assert_eq!(frame.payload, b"\x03\xe8bye");
+
+        let mut frame = WebSocketFrame::close(CloseCode::Normal, b"").unwrap();
+        frame.payload = vec![0x03, 0xee];
+        frame.header.payload_length = 2;
+        let bytes = client_bytes(frame);
+        assert_eq!(WebSocketFrame::parse(&bytes, 0, 1024).unwrap_err(),
+                   FrameError::InvalidCloseCode(1006));
+    }
+}
@@ -0,0 +1,164 @@
+//! Talks to a running server over real sockets with the blocking client.
+
+extern crate mio;
+extern crate chat;
+
+use std::env;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread;
+use std::time::Duration;
+use mio::EventLoop;
+use chat::config::Config;
+use chat::handler::EventHandler;
+use chat::protocol::{self,Envelope};
+use chat::server::Server;
+use chat::websocket_client::{WebSocketClient,Message,ClientError};
+use chat::websocket_frame::CloseCode;
+
+static NEXT_SERVER: AtomicUsize = AtomicUsize::new(0);
+
+/// Start a server on a free port, returning its "host:port" address
+fn start_server() -> String {
+    // Let the OS pick a port, then hand it to the server
+    let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    let mut config = Config::default();
+    config.address = address;
+    config.history_dir = env::temp_dir().join(format!("chat-test-history-{}-{}",
+                                                      ::std::process::id(),
+                                                      NEXT_SERVER.fetch_add(1, Ordering::SeqCst)));
+
+    thread::spawn(move || {
+        let server = Server::new(config).unwrap();
+        let mut event_handler = EventHandler::new(server);
+        let mut event_loop: EventLoop<EventHandler> = EventLoop::new().unwrap();
+        event_handler.register_server(&mut event_loop);
+        event_loop.run(&mut event_handler).unwrap();
+    });
+
+    let host = format!("{}", address);
+    for _ in 0..100 {
+        if ::std::net::TcpStream::connect(&*host).is_ok() {
+            return host;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("The server did not start");
+}
+
+/// The next chat envelope of this type, skipping any others
+fn receive_envelope(client: &mut WebSocketClient, kind: &str) -> Envelope {
+    loop {
+        match client.receive().unwrap() {
+            Message::Text(text) => {
+                let envelope = Envelope::decode(&text).unwrap();
+                if envelope.kind == kind {
+                    return envelope;
+                }
+            },
+            other => panic!("Expected a text message, got {:?}", other),
+        }
+    }
+}
+
+fn send_envelope(client: &mut WebSocketClient, envelope: Envelope) {
+    client.send_text(&envelope.encode()).unwrap();
+}
+
+/// Take a nickname and join a room, waiting for the server to confirm each
+fn join_as(client: &mut WebSocketClient, nick: &str, room: &str) {
+    let mut envelope = Envelope::new("nick");
+    envelope.body = Some(nick.to_owned());
+    send_envelope(client, envelope);
+    receive_envelope(client, "notice");
+
+    let mut envelope = Envelope::new("join");
+    envelope.room = Some(room.to_owned());
+    send_envelope(client, envelope);
+    receive_envelope(client, "notice");
+}
+
+#[test]
+fn echoes_text() {
+    let host = start_server();
+    let mut client = WebSocketClient::connect(&host, "/echo", &[]).unwrap();
+    assert_eq!(client.protocol(), None);
+
+    client.send_text("hello").unwrap();
+    assert_eq!(client.receive().unwrap(), Message::Text("hello".to_owned()));
+
+    // Long enough for a 64 bit length
+    let long: String = ::std::iter::repeat('x').take(70_000).collect();
+    client.send_text(&long).unwrap();
+    assert_eq!(client.receive().unwrap(), Message::Text(long));
+}
+
+#[test]
+fn answers_pings() {
+    let host = start_server();
+    let mut client = WebSocketClient::connect(&host, "/echo", &[]).unwrap();
+
+    client.send_ping(b"are you there").unwrap();
+    assert_eq!(client.receive().unwrap(), Message::Pong(b"are you there".to_vec()));
+}
+
+#[test]
+fn finishes_the_close_handshake() {
+    let host = start_server();
+    let mut client = WebSocketClient::connect(&host, "/echo", &[]).unwrap();
+
+    client.close(CloseCode::Normal, "bye").unwrap();
+    match client.receive().unwrap() {
+        Message::Close(code, _) => assert_eq!(code, Some(CloseCode::Normal as u16)),
+        other => panic!("Expected a close, got {:?}", other),
+    }
+    match client.receive() {
+        Err(ClientError::Closed) => { },
+        other => panic!("Expected the connection to be closed, got {:?}", other),
+    }
+}
+
+#[test]
+fn negotiates_subprotocols_per_route() {
+    let host = start_server();
+
+    let client = WebSocketClient::connect(&host, "/chat", &["other", protocol::SUBPROTOCOL]).unwrap();
+    assert_eq!(client.protocol(), Some(protocol::SUBPROTOCOL));
+
+    match WebSocketClient::connect(&host, "/echo", &[protocol::SUBPROTOCOL]) {
+        Err(ClientError::Handshake(_)) => { },
+        other => panic!("Expected /echo to refuse chat.v1, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn refuses_unknown_paths() {
+    let host = start_server();
+
+    match WebSocketClient::connect(&host, "/nowhere?access_token=secret", &[]) {
+        Err(ClientError::Handshake(reason)) => assert!(reason.contains("404")),
+        other => panic!("Expected a 404, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn chats_in_a_room() {
+    let host = start_server();
+    let mut alice = WebSocketClient::connect(&host, "/chat", &[protocol::SUBPROTOCOL]).unwrap();
+    let mut bob = WebSocketClient::connect(&host, "/chat", &[protocol::SUBPROTOCOL]).unwrap();
+
+    join_as(&mut alice, "alice", "lobby");
+    join_as(&mut bob, "bob", "lobby");
+
+    let mut envelope = Envelope::new("message");
+    envelope.room = Some("lobby".to_owned());
+    envelope.body = Some("hello, bob".to_owned());
+    send_envelope(&mut alice, envelope);
+
+    let received = receive_envelope(&mut bob, "message");
+    assert_eq!(received.from, Some("alice".to_owned()));
+    assert_eq!(received.room, Some("lobby".to_owned()));
+    assert_eq!(received.body, Some("hello, bob".to_owned()));
+    assert!(received.id.is_some());
+}